@@ -12,10 +12,10 @@ const NONCE: u64 = 123456789;
 async fn main() {
     let url =
         Url::parse("ws://oxtrdevav64z64yb7x6rjg4ntzqjhedm5b5zjqulugknhzr46ny2qbad.onion").unwrap();
-    let (mut tx, mut rx) =
-        async_wsocket::connect(&url, &ConnectionMode::tor(), Duration::from_secs(120))
-            .await
-            .unwrap();
+    let opts = ConnectionOptions::new().timeout(Duration::from_secs(120));
+    let (mut tx, mut rx) = async_wsocket::connect(&url, &ConnectionMode::tor(), opts)
+        .await
+        .unwrap();
 
     // Send ping
     let nonce = NONCE.to_be_bytes().to_vec();
@@ -0,0 +1,29 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use async_wsocket::prelude::*;
+use futures_util::{SinkExt, StreamExt};
+
+#[tokio::main]
+async fn main() {
+    // Launch hidden service, without a local listener
+    let (service, streams) = tor::launch_onion_service_raw("async-wsocket-hs-raw-server", None)
+        .await
+        .unwrap();
+    println!("{}", service.onion_name().unwrap());
+
+    let mut streams = Box::pin(streams);
+    while let Some(stream) = streams.next().await {
+        tokio::spawn(async move {
+            let stream = async_wsocket::native::accept(stream).await.unwrap();
+
+            // Echo
+            let (mut tx, mut rx) = stream.split();
+            while let Some(Ok(msg)) = rx.next().await {
+                if msg.is_text() || msg.is_binary() {
+                    tx.send(msg).await.unwrap();
+                }
+            }
+        });
+    }
+}
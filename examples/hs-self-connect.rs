@@ -0,0 +1,62 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::time::Duration;
+
+use async_wsocket::prelude::*;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() {
+    // Bind echo server
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let stream = async_wsocket::native::accept(stream).await.unwrap();
+                let (mut tx, mut rx) = stream.split();
+                while let Some(Ok(msg)) = rx.next().await {
+                    if msg.is_text() || msg.is_binary() {
+                        tx.send(msg).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    // Launch hidden service
+    let service = tor::launch_onion_service("async-wsocket-hs-self-connect", local_addr, 80, None)
+        .await
+        .unwrap();
+    println!("Launched {}", service.hostname());
+
+    // Wait for the descriptor to be published
+    service
+        .wait_until_running(Some(Duration::from_secs(300)))
+        .await
+        .unwrap();
+    println!("Service running");
+
+    // Connect to the service, using the same tor client
+    let url = Url::parse(&format!("ws://{}", service.hostname())).unwrap();
+    let (mut tx, mut rx) =
+        async_wsocket::connect(&url, &ConnectionMode::tor(), Duration::from_secs(120))
+            .await
+            .unwrap();
+
+    // Round-trip a message
+    tx.send(WsMessage::Text(String::from("hello")))
+        .await
+        .unwrap();
+
+    while let Some(msg) = rx.next().await {
+        if let Ok(WsMessage::Text(text)) = msg {
+            assert_eq!(text, "hello");
+            println!("Echo match!");
+            break;
+        }
+    }
+}
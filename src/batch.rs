@@ -0,0 +1,171 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Batched send
+
+use std::fmt;
+
+use futures_util::{Sink as SinkTrait, SinkExt};
+
+use crate::{Error, WsMessage};
+
+/// Error returned by a batched send
+#[derive(Debug)]
+pub struct SendBatchError {
+    /// Index of the message that failed to be queued
+    ///
+    /// The previous messages have been queued, but may not have been flushed.
+    /// `None` if all the messages have been queued and the final flush failed.
+    pub index: Option<usize>,
+    /// Error
+    pub error: Error,
+}
+
+impl std::error::Error for SendBatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl fmt::Display for SendBatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "failed to send message #{index}: {}", self.error),
+            None => write!(f, "failed to flush messages: {}", self.error),
+        }
+    }
+}
+
+/// Queue all the messages and flush once, stopping on the first error
+pub(crate) async fn send_batch<S, I>(sink: &mut S, msgs: I) -> Result<(), SendBatchError>
+where
+    S: SinkTrait<WsMessage, Error = Error> + Unpin,
+    I: IntoIterator<Item = WsMessage>,
+{
+    for (index, msg) in msgs.into_iter().enumerate() {
+        sink.feed(msg).await.map_err(|error| SendBatchError {
+            index: Some(index),
+            error,
+        })?;
+    }
+
+    sink.flush()
+        .await
+        .map_err(|error| SendBatchError { index: None, error })
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    use futures_util::{future, StreamExt};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::net::TcpStream;
+    use url::Url;
+
+    use super::*;
+    use crate::test_util::EchoServer;
+    use crate::{ConnectionMode, ConnectionOptions};
+
+    const MESSAGES: usize = 1000;
+
+    async fn send_batch_in_order(opts: ConnectionOptions) {
+        let (addr, _server) = EchoServer::new().spawn().await;
+        let url: Url = Url::parse(&format!("ws://{addr}")).unwrap();
+        let (mut tx, mut rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+
+        let msgs = (0..MESSAGES).map(|i| WsMessage::Text(i.to_string()));
+
+        // Received while sending: the task stops writing when the receive buffer is full
+        let receive = async {
+            for i in 0..MESSAGES {
+                assert_eq!(
+                    rx.next().await.unwrap().unwrap(),
+                    WsMessage::Text(i.to_string())
+                );
+            }
+        };
+        let (res, ()) = future::join(tx.send_batch(msgs), receive).await;
+        res.unwrap();
+
+        assert_eq!(tx.stats().messages_sent, MESSAGES as u64);
+    }
+
+    #[tokio::test]
+    async fn test_send_batch() {
+        send_batch_in_order(ConnectionOptions::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_with_send_buffer() {
+        send_batch_in_order(ConnectionOptions::new().send_buffer(64)).await;
+    }
+
+    /// TCP stream counting the flushes
+    struct Flushes {
+        stream: TcpStream,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for Flushes {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Flushes {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_flushes_once() {
+        let (addr, _server) = EchoServer::new().spawn().await;
+        let url: Url = Url::parse(&format!("ws://{addr}")).unwrap();
+        let count: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+        let stream = Flushes {
+            stream: TcpStream::connect(addr).await.unwrap(),
+            count: count.clone(),
+        };
+
+        let opts = ConnectionOptions::new().send_buffer(MESSAGES);
+        let (mut tx, mut rx) = crate::connect_on(stream, &url, opts).await.unwrap();
+        let handshake: usize = count.load(Ordering::Relaxed);
+
+        let msgs = (0..MESSAGES).map(|i| WsMessage::Text(i.to_string()));
+        tx.send_batch(msgs).await.unwrap();
+
+        for i in 0..MESSAGES {
+            let msg: WsMessage = rx.next().await.unwrap().unwrap();
+            assert_eq!(msg, WsMessage::Text(i.to_string()));
+        }
+
+        // All queued before the task writes them: flushed once, not once per message
+        let flushes: usize = count.load(Ordering::Relaxed) - handshake;
+        assert!(flushes < 10, "{flushes} flushes");
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Blocking API
+//!
+//! Wrapper of the async API for simple scripts and CLI tools, without setting up a runtime:
+//! the calls are driven by an internal current-thread tokio runtime, shared by all the connections
+//! (so the tor client, in [`ConnectionMode::EmbeddedTor`] mode, is bootstrapped once).
+//!
+//! Not meant for high-concurrency use: the background work of the connections (pongs, keepalive,
+//! idle timeout, tor circuits) only progresses while a call is blocking. Avoid the keepalive and
+//! the idle timeout if the connection may stay unused for longer than their timeouts.
+//!
+//! With the `runtime-async-std` feature (and not `runtime-tokio`), the calls are driven by `async-std` instead,
+//! and the background work runs on its global executor.
+//!
+//! The calls panic if made from an async context: use the async API there.
+
+use std::fmt;
+use std::future::Future;
+#[cfg(feature = "runtime-tokio")]
+use std::sync::OnceLock;
+
+use futures_util::future;
+use futures_util::{SinkExt, StreamExt};
+#[cfg(feature = "runtime-tokio")]
+use tokio::runtime::{Builder, Runtime};
+#[cfg(feature = "runtime-tokio")]
+use tokio_tungstenite::tungstenite::Error as WsError;
+use url::Url;
+
+use crate::{ConnectionMode, ConnectionOptions, Error, Sink, Stream, WsMessage};
+
+#[cfg(feature = "runtime-tokio")]
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// Blocking connection
+pub struct Connection {
+    sink: Sink,
+    stream: Stream,
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("id", &self.sink.id())
+            .finish()
+    }
+}
+
+/// Connect, blocking the current thread
+///
+/// See [`crate::connect`] for the details.
+pub fn connect<O>(url: &Url, mode: &ConnectionMode, opts: O) -> Result<Connection, Error>
+where
+    O: Into<ConnectionOptions>,
+{
+    let (sink, stream) = block_on(crate::connect(url, mode, opts))??;
+    Ok(Connection { sink, stream })
+}
+
+impl Connection {
+    /// Send a message and flush it
+    pub fn send(&mut self, msg: WsMessage) -> Result<(), Error> {
+        block_on(self.sink.send(msg))?
+    }
+
+    /// Wait for the next message
+    ///
+    /// Returns `None` when the connection is closed.
+    pub fn recv(&mut self) -> Option<Result<WsMessage, Error>> {
+        match block_on(self.stream.next()) {
+            Ok(msg) => msg,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Close the connection, waiting for the close frame of the server
+    ///
+    /// The messages received in the meantime are discarded.
+    pub fn close(self) -> Result<(), Error> {
+        let Self {
+            mut sink,
+            mut stream,
+        } = self;
+
+        let drain = async move { while stream.next().await.is_some() {} };
+        let (res, ()) = block_on(future::join(sink.close(), drain))?;
+        res
+    }
+}
+
+/// Run the future on the internal runtime
+#[cfg(feature = "runtime-tokio")]
+fn block_on<F>(future: F) -> Result<F::Output, Error>
+where
+    F: Future,
+{
+    Ok(runtime()?.block_on(future))
+}
+
+/// Run the future on `async-std`
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+fn block_on<F>(future: F) -> Result<F::Output, Error>
+where
+    F: Future,
+{
+    Ok(async_std::task::block_on(future))
+}
+
+#[cfg(feature = "runtime-tokio")]
+fn runtime() -> Result<&'static Runtime, Error> {
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime);
+    }
+
+    let runtime: Runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(WsError::Io)?;
+
+    // If built concurrently by another thread, the one already set is kept
+    Ok(RUNTIME.get_or_init(|| runtime))
+}
@@ -0,0 +1,16 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection id
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Get a new connection id, unique within the process
+///
+/// Used to tell apart the tracing events of concurrent connections.
+#[inline]
+pub(crate) fn next() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
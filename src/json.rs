@@ -0,0 +1,97 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! JSON messages
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::Stream as StreamTrait;
+use serde::de::DeserializeOwned;
+
+use crate::payload::Payload;
+use crate::{Error, WsMessage};
+
+/// Stream of the JSON messages, returned by `Stream::json`
+///
+/// Each text or binary message is deserialized into `T`, skipping the control messages.
+/// A malformed message yields an [`Error::Json`] and the stream continues with the next message.
+pub struct Json<S, T> {
+    stream: S,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<S, T> fmt::Debug for Json<S, T>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Json")
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl<S, T> Json<S, T> {
+    #[inline]
+    pub(crate) fn new(stream: S) -> Self {
+        Self {
+            stream,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get a reference to the underlying stream
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get a mutable reference to the underlying stream
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consume the adaptor and return the underlying stream
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S, T> StreamTrait for Json<S, T>
+where
+    S: StreamTrait<Item = Result<WsMessage, Error>> + Unpin,
+    T: DeserializeOwned,
+{
+    type Item = Result<T, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let msg: WsMessage = match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(msg))) => msg,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let res = match Payload::from_message(msg) {
+                Some(Payload::Text(text)) => serde_json::from_str(&text),
+                Some(Payload::Binary(data)) => serde_json::from_slice(&data),
+                // Control message
+                None => continue,
+            };
+
+            return Poll::Ready(Some(res.map_err(Error::from)));
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // The control messages are skipped
+        (0, self.stream.size_hint().1)
+    }
+}
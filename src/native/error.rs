@@ -2,20 +2,83 @@
 // Distributed under the MIT software license
 
 use core::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
 
-use tokio_tungstenite::tungstenite::Error as WsError;
-use url::ParseError;
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::pki_types::pem;
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::{self, CertificateError};
+#[cfg(feature = "rustls")]
+use tokio_tungstenite::tungstenite::error::TlsError;
+use tokio_tungstenite::tungstenite::error::{CapacityError, ProtocolError, UrlError};
+use tokio_tungstenite::tungstenite::http::header::RETRY_AFTER;
+use tokio_tungstenite::tungstenite::http::{HeaderMap, StatusCode};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use url::{ParseError, Url};
 
 #[cfg(feature = "tor")]
 use super::tor;
 
+/// Max captured body of a rejected handshake
+const MAX_REJECTED_BODY_LEN: usize = 4096;
+
 #[derive(Debug)]
 pub enum Error {
     /// Ws error
-    Ws(WsError),
+    Ws(Box<WsError>),
+    /// Incoming message (or frame) exceeds the configured max size
+    MessageTooLarge {
+        /// Size of the message
+        size: usize,
+        /// Max allowed size
+        max_size: usize,
+    },
+    /// Text message with invalid UTF-8
+    ///
+    /// With the background task (keepalive, idle timeout or send buffer), the connection is closed
+    /// with code `1007`. Otherwise the `Stream` can't write to the connection: it stays open until the sink
+    /// is closed with `SinkExt::close` (sending the `1007` close frame) or `Sink::close_with` (with a custom code).
+    /// Dropping the sink drops the connection without the close frame.
+    ///
+    /// The payload is discarded by `tungstenite`, so it can't be decoded lossily.
+    InvalidUtf8,
     /// Socks error
     #[cfg(feature = "socks")]
     Socks(tokio_socks::Error),
+    /// Proxy address unspecified (i.e. `0.0.0.0`) or with the port zero
+    InvalidProxyAddress(SocketAddr),
+    /// Proxy refused the connection (or is unreachable)
+    ProxyConnectionRefused,
+    /// Proxy authentication failed
+    ProxyAuthFailed,
+    /// HTTP proxy responded with a non-success status
+    HttpProxy {
+        /// Status code
+        status: u16,
+        /// Reason phrase
+        reason: String,
+    },
+    /// Malformed HTTP proxy response
+    InvalidProxyResponse,
+    /// The server rejected the WebSocket upgrade, with a non-`101` response
+    HandshakeRejected {
+        /// Status code
+        status: u16,
+        /// Response headers
+        headers: Box<HeaderMap>,
+        /// Part of the body received with the headers (up to 4 KiB)
+        body: Vec<u8>,
+    },
+    /// Redirect without a valid `Location` (or to a non-WebSocket URL)
+    InvalidRedirect,
+    /// Redirect from `wss` to `ws`
+    InsecureRedirect,
+    /// Redirect to an already visited URL
+    RedirectLoop,
+    /// Max number of redirects exceeded
+    TooManyRedirects,
     /// Tor error
     #[cfg(feature = "tor")]
     Tor(tor::Error),
@@ -23,34 +86,217 @@ pub enum Error {
     Url(ParseError),
     /// Timeout
     Timeout,
+    /// No response to the WebSocket handshake within the handshake timeout
+    HandshakeTimeout,
+    /// Connection cancelled
+    Cancelled,
+    /// All the connections of `connect_any` failed (empty if no URL was given)
+    AllConnectionsFailed(Vec<(Url, Error)>),
+    /// No pong received within the keepalive timeout (or too many unanswered pings)
+    KeepaliveTimeout,
+    /// No frame received (or sent) within the idle timeout
+    IdleTimeout,
+    /// The connection has no background task, required to change its timers
+    NoBackgroundTask,
+    /// Zero keepalive interval (or timeout) or idle timeout
+    ZeroDuration,
+    /// Write buffer size not smaller than the max write buffer size
+    InvalidWriteBufferSize {
+        /// Write buffer size
+        size: usize,
+        /// Max write buffer size
+        max_size: usize,
+    },
+    /// Send buffer full, with [`OverflowPolicy::Error`](crate::OverflowPolicy::Error)
+    SendBufferFull,
+    /// Close reason longer than 123 bytes
+    CloseReasonTooLong,
+    /// The close didn't complete within the timeout
+    PartialClose {
+        /// Messages not written
+        unsent: usize,
+    },
+    /// Binding the local address (or device) not supported in this connection mode
+    BindNotSupported,
+    /// Onion address in direct mode, without the `tor` feature
+    OnionRequiresTor,
+    /// Raw frame incomplete or followed by other data
+    InvalidFrame,
+    /// Message not deserializable from JSON
+    #[cfg(feature = "serde")]
+    Json(serde_json::Error),
+    /// Invalid PEM certificate
+    #[cfg(feature = "rustls")]
+    InvalidPem(pem::Error),
+    /// Server certificate expired (or not valid yet)
+    #[cfg(feature = "rustls")]
+    CertExpired,
+    /// Server certificate not valid for the hostname
+    #[cfg(feature = "rustls")]
+    CertHostnameMismatch,
+    /// Server certificate issued by an unknown (or untrusted) authority
+    #[cfg(feature = "rustls")]
+    CertUntrusted,
+    /// Server certificate rejected for another reason
+    #[cfg(feature = "rustls")]
+    CertOther(rustls::Error),
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Ws(e) => Some(e.as_ref()),
+            #[cfg(feature = "socks")]
+            Self::Socks(e) => Some(e),
+            #[cfg(feature = "tor")]
+            Self::Tor(e) => Some(e),
+            Self::Url(e) => Some(e),
+            #[cfg(feature = "serde")]
+            Self::Json(e) => Some(e),
+            #[cfg(feature = "rustls")]
+            Self::InvalidPem(e) => Some(e),
+            #[cfg(feature = "rustls")]
+            Self::CertOther(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Ws(e) => write!(f, "{e}"),
+            Self::MessageTooLarge { size, max_size } => {
+                write!(f, "message too large: {size} > {max_size}")
+            }
+            Self::InvalidUtf8 => write!(f, "invalid UTF-8 in text message"),
             #[cfg(feature = "socks")]
             Self::Socks(e) => write!(f, "{e}"),
+            Self::InvalidProxyAddress(addr) => write!(f, "invalid proxy address: {addr}"),
+            Self::ProxyConnectionRefused => write!(f, "proxy connection refused"),
+            Self::ProxyAuthFailed => write!(f, "proxy authentication failed"),
+            Self::HttpProxy { status, reason } => {
+                write!(f, "HTTP proxy responded with {status} {reason}")
+            }
+            Self::InvalidProxyResponse => write!(f, "invalid HTTP proxy response"),
+            Self::HandshakeRejected { status, .. } => {
+                write!(f, "handshake rejected with status {status}")
+            }
+            Self::InvalidRedirect => write!(f, "invalid redirect location"),
+            Self::InsecureRedirect => write!(f, "redirect from wss to ws rejected"),
+            Self::RedirectLoop => write!(f, "redirect loop"),
+            Self::TooManyRedirects => write!(f, "too many redirects"),
             #[cfg(feature = "tor")]
             Self::Tor(e) => write!(f, "{e}"),
             Self::Url(e) => write!(f, "{e}"),
             Self::Timeout => write!(f, "timeout"),
+            Self::HandshakeTimeout => write!(f, "handshake timeout"),
+            Self::Cancelled => write!(f, "connection cancelled"),
+            Self::AllConnectionsFailed(errors) => {
+                if errors.is_empty() {
+                    return write!(f, "no URL to connect to");
+                }
+
+                write!(f, "all the {} connections failed", errors.len())?;
+                for (url, e) in errors {
+                    write!(f, "; {url}: {e}")?;
+                }
+                Ok(())
+            }
+            Self::KeepaliveTimeout => write!(f, "keepalive timeout: no pong received"),
+            Self::IdleTimeout => write!(f, "idle timeout: no activity"),
+            Self::NoBackgroundTask => write!(
+                f,
+                "no background task: enable keepalive, the idle timeout or the send buffer"
+            ),
+            Self::ZeroDuration => write!(f, "duration must be greater than zero"),
+            Self::InvalidWriteBufferSize { size, max_size } => write!(
+                f,
+                "invalid write buffer size: {size} (must be smaller than the max {max_size})"
+            ),
+            Self::SendBufferFull => write!(f, "send buffer full"),
+            Self::CloseReasonTooLong => write!(f, "close reason too long (max 123 bytes)"),
+            Self::PartialClose { unsent } => {
+                write!(f, "close timeout: {unsent} messages not sent")
+            }
+            Self::BindNotSupported => write!(f, "local address binding not supported"),
+            Self::OnionRequiresTor => write!(
+                f,
+                "onion address requires the `tor` feature (or a proxy connection mode)"
+            ),
+            Self::InvalidFrame => write!(f, "invalid raw frame: not exactly one complete frame"),
+            #[cfg(feature = "serde")]
+            Self::Json(e) => write!(f, "invalid JSON message: {e}"),
+            #[cfg(feature = "rustls")]
+            Self::InvalidPem(e) => write!(f, "invalid PEM: {e}"),
+            #[cfg(feature = "rustls")]
+            Self::CertExpired => write!(f, "certificate expired or not valid yet"),
+            #[cfg(feature = "rustls")]
+            Self::CertHostnameMismatch => write!(f, "certificate not valid for the hostname"),
+            #[cfg(feature = "rustls")]
+            Self::CertUntrusted => write!(f, "certificate issued by an untrusted authority"),
+            #[cfg(feature = "rustls")]
+            Self::CertOther(e) => write!(f, "{e}"),
         }
     }
 }
 
 impl From<WsError> for Error {
     fn from(e: WsError) -> Self {
-        Self::Ws(e)
+        match e {
+            WsError::Capacity(CapacityError::MessageTooLong { size, max_size }) => {
+                Self::MessageTooLarge { size, max_size }
+            }
+            WsError::Utf8 => Self::InvalidUtf8,
+            WsError::Http(response) => {
+                let (parts, body) = response.into_parts();
+                let mut body: Vec<u8> = body.unwrap_or_default();
+                body.truncate(MAX_REJECTED_BODY_LEN);
+                Self::HandshakeRejected {
+                    status: parts.status.as_u16(),
+                    headers: Box::new(parts.headers),
+                    body,
+                }
+            }
+            #[cfg(feature = "rustls")]
+            WsError::Io(e) => Self::from_tls_io(e),
+            e => Self::Ws(Box::new(e)),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::from(WsError::Io(e))
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Self::Url(e)
     }
 }
 
 #[cfg(feature = "socks")]
 impl From<tokio_socks::Error> for Error {
     fn from(e: tokio_socks::Error) -> Self {
-        Self::Socks(e)
+        match e {
+            tokio_socks::Error::ProxyServerUnreachable => Self::ProxyConnectionRefused,
+            tokio_socks::Error::Io(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                Self::ProxyConnectionRefused
+            }
+            tokio_socks::Error::PasswordAuthFailure(..)
+            | tokio_socks::Error::NoAcceptableAuthMethods
+            | tokio_socks::Error::AuthorizationRequired => Self::ProxyAuthFailed,
+            e => Self::Socks(e),
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<pem::Error> for Error {
+    fn from(e: pem::Error) -> Self {
+        Self::InvalidPem(e)
     }
 }
 
@@ -61,16 +307,252 @@ impl From<tor::Error> for Error {
     }
 }
 
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
 impl Error {
     #[inline]
-    #[cfg(any(feature = "socks", feature = "tor"))]
     pub(super) fn empty_host() -> Self {
         Self::Url(ParseError::EmptyHost)
     }
 
     #[inline]
-    #[cfg(any(feature = "socks", feature = "tor"))]
     pub(super) fn invalid_port() -> Self {
         Self::Url(ParseError::InvalidPort)
     }
+
+    #[inline]
+    pub(super) fn unsupported_scheme() -> Self {
+        Self::Ws(Box::new(WsError::Url(UrlError::UnsupportedUrlScheme)))
+    }
+
+    /// Map the failures of the TLS handshake (reported by `tokio-rustls` as I/O errors)
+    ///
+    /// The certificate verification failures have their own variants, the other ones (i.e. no ALPN protocol
+    /// accepted by the server) are TLS errors.
+    #[cfg(feature = "rustls")]
+    fn from_tls_io(e: io::Error) -> Self {
+        let tls: Option<&rustls::Error> =
+            e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>());
+
+        match tls {
+            Some(rustls::Error::InvalidCertificate(cert)) => match cert {
+                CertificateError::Expired | CertificateError::NotValidYet => Self::CertExpired,
+                CertificateError::NotValidForName => Self::CertHostnameMismatch,
+                CertificateError::UnknownIssuer => Self::CertUntrusted,
+                cert => Self::CertOther(rustls::Error::InvalidCertificate(cert.clone())),
+            },
+            Some(tls) => Self::Ws(Box::new(WsError::Tls(TlsError::Rustls(tls.clone())))),
+            None => Self::Ws(Box::new(WsError::Io(e))),
+        }
+    }
+}
+
+impl Error {
+    /// Get the delay requested by the `Retry-After` header of a rejected handshake (i.e. with status `429` or `503`)
+    ///
+    /// Both the delay in seconds and the HTTP date are supported: a date in the past means no delay.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::HandshakeRejected { headers, .. } => {
+                let value: &str = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+                if let Ok(secs) = value.parse() {
+                    return Some(Duration::from_secs(secs));
+                }
+
+                let date: SystemTime = httpdate::parse_http_date(value).ok()?;
+                Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+            }
+            _ => None,
+        }
+    }
+
+    /// Check if the error is transient, so the connection may be retried
+    ///
+    /// Errors like an invalid URL or a rejected TLS certificate are fatal.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Ws(e) => matches!(
+                **e,
+                WsError::Io(..)
+                    | WsError::ConnectionClosed
+                    | WsError::AlreadyClosed
+                    | WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake)
+            ),
+            Self::MessageTooLarge { .. } => false,
+            Self::InvalidUtf8 => false,
+            #[cfg(feature = "socks")]
+            Self::Socks(e) => matches!(
+                e,
+                tokio_socks::Error::Io(..)
+                    | tokio_socks::Error::GeneralSocksServerFailure
+                    | tokio_socks::Error::NetworkUnreachable
+                    | tokio_socks::Error::HostUnreachable
+                    | tokio_socks::Error::ConnectionRefused
+                    | tokio_socks::Error::TtlExpired
+            ),
+            Self::InvalidProxyAddress(..) => false,
+            Self::ProxyConnectionRefused => true,
+            Self::ProxyAuthFailed => false,
+            Self::HttpProxy { status, .. } => {
+                // Bad gateway, service unavailable and gateway timeout
+                matches!(status, 502..=504)
+            }
+            Self::InvalidProxyResponse => false,
+            Self::HandshakeRejected { status, .. } => {
+                *status >= 500 || *status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+            }
+            Self::InvalidRedirect
+            | Self::InsecureRedirect
+            | Self::RedirectLoop
+            | Self::TooManyRedirects => false,
+            #[cfg(feature = "tor")]
+            Self::Tor(e) => matches!(
+                e,
+                tor::Error::ArtiClient(..)
+                    | tor::Error::Bootstrap(..)
+                    | tor::Error::Connect { .. }
+                    | tor::Error::BootstrapTimeout
+            ),
+            Self::Url(..) => false,
+            Self::Timeout => true,
+            Self::HandshakeTimeout => true,
+            Self::Cancelled => false,
+            Self::AllConnectionsFailed(errors) => errors.iter().any(|(_, e)| e.is_retryable()),
+            Self::KeepaliveTimeout => true,
+            Self::IdleTimeout => true,
+            Self::NoBackgroundTask => false,
+            Self::ZeroDuration => false,
+            Self::InvalidWriteBufferSize { .. } => false,
+            Self::SendBufferFull => false,
+            Self::CloseReasonTooLong => false,
+            Self::PartialClose { .. } => false,
+            Self::BindNotSupported => false,
+            Self::OnionRequiresTor => false,
+            Self::InvalidFrame => false,
+            #[cfg(feature = "serde")]
+            Self::Json(..) => false,
+            #[cfg(feature = "rustls")]
+            Self::InvalidPem(..) => false,
+            #[cfg(feature = "rustls")]
+            Self::CertExpired
+            | Self::CertHostnameMismatch
+            | Self::CertUntrusted
+            | Self::CertOther(..) => false,
+        }
+    }
+}
+
+/// Error returned by `Sink::try_send`, holding the message that wasn't queued
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrySendError {
+    /// Send buffer full
+    Full(Message),
+    /// Connection closed
+    Closed(Message),
+    /// Send buffer not enabled
+    NotBuffered(Message),
+    /// Rate limit exceeded
+    RateLimited(Message),
+}
+
+impl std::error::Error for TrySendError {}
+
+impl fmt::Display for TrySendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(..) => write!(f, "send buffer full"),
+            Self::Closed(..) => write!(f, "connection closed"),
+            Self::NotBuffered(..) => write!(f, "send buffer not enabled"),
+            Self::RateLimited(..) => write!(f, "rate limit exceeded"),
+        }
+    }
+}
+
+impl TrySendError {
+    /// Get back the message that wasn't queued
+    #[inline]
+    pub fn into_inner(self) -> Message {
+        match self {
+            Self::Full(msg)
+            | Self::Closed(msg)
+            | Self::NotBuffered(msg)
+            | Self::RateLimited(msg) => msg,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    use super::*;
+    use crate::RetryConfig;
+
+    fn rejected(retry_after: &str) -> Error {
+        let mut headers: HeaderMap = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        Error::HandshakeRejected {
+            status: 429,
+            headers: Box::new(headers),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_retry_after_seconds() {
+        assert_eq!(
+            rejected("120").retry_after(),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(rejected(" 5 ").retry_after(), Some(Duration::from_secs(5)));
+        assert_eq!(rejected("soon").retry_after(), None);
+    }
+
+    #[test]
+    fn test_retry_after_http_date() {
+        let date: SystemTime = SystemTime::now() + Duration::from_secs(300);
+        let delay: Duration = rejected(&httpdate::fmt_http_date(date))
+            .retry_after()
+            .unwrap();
+        // The HTTP date has a resolution of one second
+        assert!(delay > Duration::from_secs(298) && delay <= Duration::from_secs(300));
+
+        // In the past
+        assert_eq!(
+            rejected("Wed, 21 Oct 2015 07:28:00 GMT").retry_after(),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_retry_after_other_errors() {
+        assert_eq!(Error::Timeout.retry_after(), None);
+
+        let e = Error::HandshakeRejected {
+            status: 503,
+            headers: Box::default(),
+            body: Vec::new(),
+        };
+        assert_eq!(e.retry_after(), None);
+    }
+
+    #[test]
+    fn test_max_retry_after() {
+        let retry = RetryConfig {
+            max_retry_after: Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        let retry_after: Option<Duration> = rejected("3600").retry_after();
+        assert_eq!(retry.delay_after(1, retry_after), Duration::from_secs(60));
+
+        let retry_after: Option<Duration> = rejected("10").retry_after();
+        assert_eq!(retry.delay_after(1, retry_after), Duration::from_secs(10));
+    }
 }
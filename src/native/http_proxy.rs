@@ -0,0 +1,104 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! HTTP CONNECT proxy
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+use super::error::Error;
+use super::runtime::TcpStream;
+
+/// Max size of the proxy response head
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+
+pub(crate) struct TcpHttpConnectStream;
+
+impl TcpHttpConnectStream {
+    /// Open a tunnel to `host:port` through the HTTP proxy
+    pub async fn connect(
+        mut stream: TcpStream,
+        host: &str,
+        port: u16,
+        auth: Option<(&str, &str)>,
+    ) -> Result<TcpStream, Error> {
+        let mut request: String =
+            format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if let Some((username, password)) = auth {
+            let credentials: String = base64(format!("{username}:{password}").as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+        }
+        request.push_str("\r\n");
+
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(WsError::Io)?;
+
+        let head: String = read_response_head(&mut stream).await?;
+        let (status, reason) = parse_status_line(&head)?;
+
+        match status {
+            200..=299 => Ok(stream),
+            407 => Err(Error::ProxyAuthFailed),
+            status => Err(Error::HttpProxy {
+                status,
+                reason: reason.to_string(),
+            }),
+        }
+    }
+}
+
+/// Read the response head, byte by byte to not consume data of the tunnel
+async fn read_response_head(stream: &mut TcpStream) -> Result<String, Error> {
+    let mut head: Vec<u8> = Vec::with_capacity(128);
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_SIZE {
+            return Err(Error::InvalidProxyResponse);
+        }
+
+        let byte: u8 = stream.read_u8().await.map_err(WsError::Io)?;
+        head.push(byte);
+    }
+
+    String::from_utf8(head).map_err(|_| Error::InvalidProxyResponse)
+}
+
+/// Parse the status line (i.e. `HTTP/1.1 200 Connection established`)
+fn parse_status_line(head: &str) -> Result<(u16, &str), Error> {
+    let line: &str = head.lines().next().unwrap_or_default();
+    let mut parts = line.splitn(3, ' ');
+
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => {
+            let status: u16 = status.parse().map_err(|_| Error::InvalidProxyResponse)?;
+            Ok((status, parts.next().unwrap_or_default().trim()))
+        }
+        _ => Err(Error::InvalidProxyResponse),
+    }
+}
+
+/// Standard base64 encoding (RFC 4648), for the basic authentication
+pub(super) fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output: String = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let n: u32 = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |n, (i, byte)| n | u32::from(*byte) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(char::from(ALPHABET[(n >> (18 - 6 * i) & 63) as usize]));
+            } else {
+                output.push('=');
+            }
+        }
+    }
+
+    output
+}
@@ -3,123 +3,524 @@
 
 //! Native
 
-#[cfg(feature = "socks")]
+use std::borrow::Cow;
 use std::net::SocketAddr;
-#[cfg(feature = "tor")]
-use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "tor")]
 use arti_client::DataStream;
-use futures_util::StreamExt;
 use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "rustls")]
+pub use tokio_rustls::rustls;
 #[cfg(feature = "socks")]
-use tokio::net::TcpStream;
-use tokio::time;
-use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_socks::TargetAddr;
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+pub use tokio_tungstenite::tungstenite::http::HeaderMap;
+pub use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+pub use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
 pub use tokio_tungstenite::tungstenite::Message;
 pub use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::{Connector, MaybeTlsStream};
+use tracing::Instrument;
 use url::Url;
 
 mod error;
+mod http_proxy;
+mod queue;
+mod rate_limit;
+mod request;
+pub(crate) mod runtime;
 #[cfg(feature = "socks")]
 mod socks;
 mod stream;
+mod target;
+mod task;
+mod tcp;
+mod timings;
+mod tls;
 #[cfg(feature = "tor")]
 pub mod tor;
 
-pub use self::error::Error;
+pub use self::error::{Error, TrySendError};
+use self::http_proxy::TcpHttpConnectStream;
+use self::runtime::TcpStream;
+#[cfg(feature = "tor")]
+use self::runtime::TorStream;
 #[cfg(feature = "socks")]
 use self::socks::TcpSocks5Stream;
-use self::stream::WebSocket;
+use self::stream::{Metadata, Transport, WebSocket};
 pub use self::stream::{Sink, Stream};
-use crate::ConnectionMode;
+use self::target::Target;
+pub use self::tcp::{DnsResolver, ResolveFuture};
+pub use self::timings::ConnectTimings;
+#[cfg(feature = "tor")]
+use self::tor::TorConfig;
+use crate::{id, ConnectionMode, ConnectionOptions};
 
 pub async fn connect(
     url: &Url,
     mode: &ConnectionMode,
-    timeout: Duration,
+    opts: &ConnectionOptions,
 ) -> Result<(Sink, Stream), Error> {
-    let stream: WebSocket = match mode {
-        ConnectionMode::Direct => connect_direct(url, timeout).await?,
+    let id: u64 = id::next();
+    let span = tracing::debug_span!("connect", id, %url);
+
+    tracing::debug!(parent: &span, "Connecting.");
+
+    let start: Instant = Instant::now();
+    let mut timings: ConnectTimings = ConnectTimings::default();
+
+    // The timeout covers the whole connection process (proxy negotiation, tor bootstrap and redirects included)
+    let (stream, response) = runtime::timeout(
+        opts.timeout,
+        connect_with_redirects(url, mode, opts, &mut timings),
+    )
+    .instrument(span.clone())
+    .await
+    .unwrap_or(Err(Error::Timeout))
+    .inspect_err(|e| tracing::debug!(parent: &span, error = %e, "Connection failed."))?;
+
+    timings.total = start.elapsed();
+
+    tracing::debug!(parent: &span, ?timings, "Connected.");
+
+    let metadata: Metadata = Metadata::new(
+        id,
+        response.into_parts().0.headers,
+        stream.peer_addr(),
+        stream.local_addr(),
+        timings,
+    );
+
+    Ok(stream.split(metadata, opts))
+}
+
+/// Connect over a transport opened by the caller
+pub async fn connect_on<S>(
+    stream: S,
+    url: &Url,
+    opts: &ConnectionOptions,
+) -> Result<(Sink, Stream), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let id: u64 = id::next();
+    let span = tracing::debug_span!("connect", id, %url);
+
+    tracing::debug!(parent: &span, "Connecting over a custom transport.");
+
+    let start: Instant = Instant::now();
+    let mut timings: ConnectTimings = ConnectTimings::default();
+
+    let (stream, response) =
+        runtime::timeout(opts.timeout, handshake_on(stream, url, opts, &mut timings))
+            .instrument(span.clone())
+            .await
+            .unwrap_or(Err(Error::Timeout))
+            .inspect_err(|e| tracing::debug!(parent: &span, error = %e, "Connection failed."))?;
+
+    timings.total = start.elapsed();
+
+    tracing::debug!(parent: &span, ?timings, "Connected.");
+
+    let metadata: Metadata =
+        Metadata::new(id, response.into_parts().0.headers, None, None, timings);
+
+    Ok(stream.split(metadata, opts))
+}
+
+fn ws_config(opts: &ConnectionOptions) -> Result<WebSocketConfig, Error> {
+    let max_write_buffer_size: usize = opts.max_write_buffer_size.unwrap_or(usize::MAX);
+
+    // `tungstenite` panics otherwise
+    if max_write_buffer_size <= opts.write_buffer_size {
+        return Err(Error::InvalidWriteBufferSize {
+            size: opts.write_buffer_size,
+            max_size: max_write_buffer_size,
+        });
+    }
+
+    Ok(WebSocketConfig {
+        max_message_size: opts.max_message_size,
+        max_frame_size: opts.max_frame_size,
+        write_buffer_size: opts.write_buffer_size,
+        max_write_buffer_size,
+        ..Default::default()
+    })
+}
+
+/// Upgrade the stream to TLS, if required, and perform the WebSocket handshake
+async fn handshake<S>(
+    request: Request,
+    stream: S,
+    config: WebSocketConfig,
+    connector: Option<Connector>,
+    timeout: Option<Duration>,
+    timings: &mut ConnectTimings,
+) -> Result<(WebSocketStream<MaybeTlsStream<S>>, Response), Error>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let start: Instant = Instant::now();
+    let stream: MaybeTlsStream<S> = tls::wrap_stream(&request, stream, connector).await?;
+
+    if !matches!(stream, MaybeTlsStream::Plain(..)) {
+        timings.tls = Some(start.elapsed());
+    }
+
+    tracing::debug!("Starting handshake.");
+
+    let start: Instant = Instant::now();
+    let handshake = tokio_tungstenite::client_async_with_config(request, stream, Some(config));
+    let res = match timeout {
+        Some(timeout) => runtime::timeout(timeout, handshake)
+            .await
+            .map_err(|_| Error::HandshakeTimeout)??,
+        None => handshake.await?,
+    };
+    timings.handshake = start.elapsed();
+
+    tracing::debug!(status = %res.1.status(), "Handshake completed.");
+
+    Ok(res)
+}
+
+/// Perform the handshakes (TLS, if required, and WebSocket) over the transport of the caller
+async fn handshake_on<S>(
+    stream: S,
+    url: &Url,
+    opts: &ConnectionOptions,
+    timings: &mut ConnectTimings,
+) -> Result<(WebSocket, Response), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Check the scheme
+    Target::parse(url)?;
+
+    let request: Request = request::build(url, opts.host_override.as_deref(), &opts.headers, opts)?;
+    let config: WebSocketConfig = ws_config(opts)?;
+    let connector: Option<Connector> = tls::connector(opts)?;
+    let stream: Box<dyn Transport> = Box::new(stream);
+
+    // NOT REMOVE `Box::pin`!
+    // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
+    let (stream, response) = Box::pin(handshake(
+        request,
+        stream,
+        config,
+        connector,
+        opts.handshake_timeout,
+        timings,
+    ))
+    .await?;
+    Ok((WebSocket::Custom(stream), response))
+}
+
+/// Connect, following the handshake redirects (if enabled)
+async fn connect_with_redirects(
+    url: &Url,
+    mode: &ConnectionMode,
+    opts: &ConnectionOptions,
+    timings: &mut ConnectTimings,
+) -> Result<(WebSocket, Response), Error> {
+    let mut visited: Vec<Url> = vec![url.clone()];
+
+    loop {
+        // Only the phases of the last connection
+        *timings = ConnectTimings::default();
+
+        let url: &Url = visited.last().expect("at least one URL");
+        let target: Target = Target::parse(url)?;
+        // The host override applies only to the initial URL
+        let host: Option<&str> = match visited.len() {
+            1 => opts.host_override.as_deref(),
+            _ => None,
+        };
+        // The custom headers (i.e. `Authorization` or `Cookie`) aren't sent to the other origins
+        let headers: &[(String, String)] = if url.origin() == visited[0].origin() {
+            &opts.headers
+        } else {
+            &[]
+        };
+        let request: Request = request::build(url, host, headers, opts)?;
+
+        // NOT REMOVE `Box::pin`!
+        // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
+        let res = Box::pin(connect_with_mode(&target, request, mode, opts, timings)).await;
+
+        let headers: HeaderMap = match res {
+            Err(Error::HandshakeRejected {
+                status, headers, ..
+            }) if (300..400).contains(&status) && opts.max_redirects > 0 => *headers,
+            res => return res,
+        };
+
+        if visited.len() > opts.max_redirects {
+            return Err(Error::TooManyRedirects);
+        }
+
+        let location: Url = request::redirect_url(url, &headers)?;
+
+        if visited.contains(&location) {
+            return Err(Error::RedirectLoop);
+        }
+
+        tracing::debug!("Redirected from {url} to {location}.");
+
+        visited.push(location);
+    }
+}
+
+async fn connect_with_mode(
+    target: &Target<'_>,
+    request: Request,
+    mode: &ConnectionMode,
+    opts: &ConnectionOptions,
+    timings: &mut ConnectTimings,
+) -> Result<(WebSocket, Response), Error> {
+    let config: WebSocketConfig = ws_config(opts)?;
+    let connector: Option<Connector> = tls::connector(opts)?;
+    let mode: Cow<ConnectionMode> = route(target, mode, opts)?;
+    match mode.as_ref() {
+        ConnectionMode::Direct => {
+            connect_direct(target, request, config, connector, opts, timings).await
+        }
+        #[cfg(feature = "socks")]
+        ConnectionMode::Socks5 { addr, auth } => {
+            connect_proxy(
+                target,
+                request,
+                config,
+                connector,
+                opts,
+                *addr,
+                auth.as_ref(),
+                timings,
+            )
+            .await
+        }
         #[cfg(feature = "socks")]
-        ConnectionMode::Proxy(proxy) => connect_proxy(url, *proxy, timeout).await?,
+        #[allow(deprecated)]
+        ConnectionMode::Proxy(addr) => {
+            connect_proxy(
+                target, request, config, connector, opts, *addr, None, timings,
+            )
+            .await
+        }
+        #[cfg(feature = "socks")]
+        ConnectionMode::TorSocks5 { addr, isolation } => {
+            // Tor accepts any credentials, using them to isolate the streams
+            let auth: Option<(String, String)> = isolation
+                .as_ref()
+                .map(|token| (token.clone(), token.clone()));
+            connect_proxy(
+                target,
+                request,
+                config,
+                connector,
+                opts,
+                *addr,
+                auth.as_ref(),
+                timings,
+            )
+            .await
+        }
+        ConnectionMode::HttpConnect { addr, auth } => {
+            connect_http_proxy(
+                target,
+                request,
+                config,
+                connector,
+                opts,
+                *addr,
+                auth.as_ref(),
+                timings,
+            )
+            .await
+        }
         #[cfg(feature = "tor")]
+        #[allow(deprecated)]
         ConnectionMode::Tor { custom_path } => {
-            connect_tor(url, timeout, custom_path.as_ref()).await?
-        }
-    };
+            // The tor client manages the connections
+            if opts.is_bound() {
+                return Err(Error::BindNotSupported);
+            }
 
-    match stream {
-        WebSocket::Std(stream) => {
-            let (tx, rx) = stream.split();
-            Ok((Sink::Std(tx), Stream::Std(rx)))
+            let tor_config: TorConfig = TorConfig {
+                custom_path: custom_path.clone(),
+                ..Default::default()
+            };
+            connect_tor(
+                target,
+                request,
+                config,
+                connector,
+                opts,
+                &tor_config,
+                timings,
+            )
+            .await
         }
         #[cfg(feature = "tor")]
-        WebSocket::Tor(stream) => {
-            let (tx, rx) = stream.split();
-            Ok((Sink::Tor(tx), Stream::Tor(rx)))
+        ConnectionMode::EmbeddedTor(tor_config) => {
+            // The tor client manages the connections
+            if opts.is_bound() {
+                return Err(Error::BindNotSupported);
+            }
+
+            connect_tor(
+                target, request, config, connector, opts, tor_config, timings,
+            )
+            .await
+        }
+    }
+}
+
+/// Route the onion addresses through tor in direct mode (unless disabled)
+fn route<'a>(
+    target: &Target<'_>,
+    mode: &'a ConnectionMode,
+    opts: &ConnectionOptions,
+) -> Result<Cow<'a, ConnectionMode>, Error> {
+    match mode {
+        ConnectionMode::Direct if opts.auto_route_onion && target.is_onion() => {
+            #[cfg(feature = "tor")]
+            return Ok(Cow::Owned(
+                ConnectionMode::EmbeddedTor(TorConfig::default()),
+            ));
+
+            #[cfg(not(feature = "tor"))]
+            return Err(Error::OnionRequiresTor);
         }
+        mode => Ok(Cow::Borrowed(mode)),
     }
 }
 
-async fn connect_direct(url: &Url, timeout: Duration) -> Result<WebSocket, Error> {
+async fn connect_direct(
+    target: &Target<'_>,
+    request: Request,
+    config: WebSocketConfig,
+    connector: Option<Connector>,
+    opts: &ConnectionOptions,
+    timings: &mut ConnectTimings,
+) -> Result<(WebSocket, Response), Error> {
+    let conn: TcpStream = tcp::connect(target, opts, timings).await?;
     // NOT REMOVE `Box::pin`!
     // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
-    let (stream, _) = Box::pin(time::timeout(
-        timeout,
-        tokio_tungstenite::connect_async(url.as_str()),
+    let (stream, response) = Box::pin(handshake(
+        request,
+        conn,
+        config,
+        connector,
+        opts.handshake_timeout,
+        timings,
     ))
-    .await
-    .map_err(|_| Error::Timeout)??;
-    Ok(WebSocket::Std(stream))
+    .await?;
+    Ok((WebSocket::Std(stream), response))
 }
 
 #[cfg(feature = "socks")]
+#[allow(clippy::too_many_arguments)]
 async fn connect_proxy(
-    url: &Url,
+    target: &Target<'_>,
+    request: Request,
+    config: WebSocketConfig,
+    connector: Option<Connector>,
+    opts: &ConnectionOptions,
     proxy: SocketAddr,
-    timeout: Duration,
-) -> Result<WebSocket, Error> {
-    let host: &str = url.host_str().ok_or_else(Error::empty_host)?;
-    let port: u16 = url
-        .port_or_known_default()
-        .ok_or_else(Error::invalid_port)?;
-    let addr: String = format!("{host}:{port}");
-
-    let conn: TcpStream = TcpSocks5Stream::connect(proxy, addr).await?;
+    auth: Option<&(String, String)>,
+    timings: &mut ConnectTimings,
+) -> Result<(WebSocket, Response), Error> {
+    let addr: TargetAddr = match target.socket_addr() {
+        Some(addr) => TargetAddr::Ip(addr),
+        None => TargetAddr::Domain(Cow::Borrowed(target.host_str), target.port),
+    };
+
+    let proxy: TcpStream = tcp::connect_proxy(proxy, opts, timings).await?;
+
+    let start: Instant = Instant::now();
+    let conn: TcpStream = match auth {
+        Some((username, password)) => {
+            TcpSocks5Stream::connect_with_password(proxy, addr, username, password).await?
+        }
+        None => TcpSocks5Stream::connect(proxy, addr).await?,
+    };
+    timings.proxy = Some(start.elapsed());
+
     // NOT REMOVE `Box::pin`!
     // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
-    let (stream, _) = Box::pin(time::timeout(
-        timeout,
-        tokio_tungstenite::client_async_tls(url.as_str(), conn),
+    let (stream, response) = Box::pin(handshake(
+        request,
+        conn,
+        config,
+        connector,
+        opts.handshake_timeout,
+        timings,
     ))
-    .await
-    .map_err(|_| Error::Timeout)??;
-    Ok(WebSocket::Std(stream))
+    .await?;
+    Ok((WebSocket::Std(stream), response))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn connect_http_proxy(
+    target: &Target<'_>,
+    request: Request,
+    config: WebSocketConfig,
+    connector: Option<Connector>,
+    opts: &ConnectionOptions,
+    proxy: SocketAddr,
+    auth: Option<&(String, String)>,
+    timings: &mut ConnectTimings,
+) -> Result<(WebSocket, Response), Error> {
+    let auth = auth.map(|(username, password)| (username.as_str(), password.as_str()));
+    let proxy: TcpStream = tcp::connect_proxy(proxy, opts, timings).await?;
+
+    let start: Instant = Instant::now();
+    let conn: TcpStream =
+        TcpHttpConnectStream::connect(proxy, target.host_str, target.port, auth).await?;
+    timings.proxy = Some(start.elapsed());
+
+    // NOT REMOVE `Box::pin`!
+    // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
+    let (stream, response) = Box::pin(handshake(
+        request,
+        conn,
+        config,
+        connector,
+        opts.handshake_timeout,
+        timings,
+    ))
+    .await?;
+    Ok((WebSocket::Std(stream), response))
 }
 
 #[cfg(feature = "tor")]
 async fn connect_tor(
-    url: &Url,
-    timeout: Duration,
-    custom_path: Option<&PathBuf>,
-) -> Result<WebSocket, Error> {
-    let host: &str = url.host_str().ok_or_else(Error::empty_host)?;
-    let port: u16 = url
-        .port_or_known_default()
-        .ok_or_else(Error::invalid_port)?;
-
-    let conn: DataStream = tor::connect(host, port, custom_path).await?;
+    target: &Target<'_>,
+    request: Request,
+    config: WebSocketConfig,
+    connector: Option<Connector>,
+    opts: &ConnectionOptions,
+    tor_config: &TorConfig,
+    timings: &mut ConnectTimings,
+) -> Result<(WebSocket, Response), Error> {
+    let conn: DataStream =
+        tor::connect(target.host_unbracketed(), target.port, tor_config, timings).await?;
+    let conn: TorStream = runtime::tor_stream(conn);
     // NOT REMOVE `Box::pin`!
     // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
-    let (stream, _) = Box::pin(time::timeout(
-        timeout,
-        tokio_tungstenite::client_async_tls(url.as_str(), conn),
+    let (stream, response) = Box::pin(handshake(
+        request,
+        conn,
+        config,
+        connector,
+        opts.handshake_timeout,
+        timings,
     ))
-    .await
-    .map_err(|_| Error::Timeout)??;
-    Ok(WebSocket::Tor(stream))
+    .await?;
+    Ok((WebSocket::Tor(stream), response))
 }
 
 #[inline]
@@ -140,3 +541,140 @@ where
 {
     WebSocketStream::from_raw_socket(raw_stream, Role::Server, None).await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{
+        self, ErrorResponse, Request as ServerRequest,
+    };
+
+    use super::*;
+    use crate::test_util::raw;
+
+    type Recorded = Arc<Mutex<Vec<HeaderMap>>>;
+
+    /// Server redirecting the first connection to `location` (if any), then accepting the connections
+    ///
+    /// The headers of the accepted requests are recorded.
+    async fn server(location: Option<String>) -> (Url, Recorded) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: Url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let recorded: Recorded = Arc::new(Mutex::new(Vec::new()));
+
+        let headers: Recorded = recorded.clone();
+        tokio::spawn(async move {
+            let mut location: Option<String> = location;
+
+            while let Ok((mut stream, _)) = listener.accept().await {
+                if let Some(location) = location.take() {
+                    raw::read_request(&mut stream).await;
+                    let response: String = format!(
+                        "HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\n\r\n"
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    continue;
+                }
+
+                let headers: Recorded = headers.clone();
+                tokio::spawn(async move {
+                    // The error response is set by `tungstenite`
+                    #[allow(clippy::result_large_err)]
+                    let callback = |request: &ServerRequest, response: server::Response| {
+                        headers.lock().unwrap().push(request.headers().clone());
+                        Ok::<server::Response, ErrorResponse>(response)
+                    };
+                    let mut ws = tokio_tungstenite::accept_hdr_async(stream, callback)
+                        .await
+                        .unwrap();
+                    while let Some(Ok(..)) = ws.next().await {}
+                });
+            }
+        });
+
+        (url, recorded)
+    }
+
+    fn opts() -> ConnectionOptions {
+        ConnectionOptions::new()
+            .max_redirects(1)
+            .header("Authorization", "Bearer secret")
+            .header("Cookie", "session=secret")
+            .header("X-Custom", "value")
+    }
+
+    #[tokio::test]
+    async fn test_redirect_same_origin() {
+        let (url, recorded) = server(Some(String::from("/other"))).await;
+
+        connect(&url, &ConnectionMode::Direct, &opts())
+            .await
+            .unwrap();
+
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0]["authorization"], "Bearer secret");
+        assert_eq!(recorded[0]["cookie"], "session=secret");
+        assert_eq!(recorded[0]["x-custom"], "value");
+    }
+
+    #[tokio::test]
+    async fn test_redirect_cross_origin() {
+        let (target, recorded) = server(None).await;
+        let (url, ..) = server(Some(target.to_string())).await;
+
+        connect(&url, &ConnectionMode::Direct, &opts())
+            .await
+            .unwrap();
+
+        // Other port: the credentials aren't leaked
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert!(!recorded[0].contains_key("authorization"));
+        assert!(!recorded[0].contains_key("cookie"));
+        assert!(!recorded[0].contains_key("x-custom"));
+    }
+
+    #[tokio::test]
+    async fn test_redirect_disabled() {
+        let (target, ..) = server(None).await;
+        let (url, ..) = server(Some(target.to_string())).await;
+
+        let res = connect(&url, &ConnectionMode::Direct, &ConnectionOptions::new()).await;
+        assert!(matches!(
+            res,
+            Err(Error::HandshakeRejected { status: 302, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connect_on() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut ws = tokio_tungstenite::accept_async(server).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_text() || msg.is_binary() {
+                    ws.send(msg).await.unwrap();
+                }
+            }
+        });
+
+        let url: Url = Url::parse("ws://localhost/path").unwrap();
+        let (mut tx, mut rx) = connect_on(client, &url, &ConnectionOptions::new())
+            .await
+            .unwrap();
+
+        // Not a TCP connection
+        assert_eq!(tx.peer_addr(), None);
+
+        let msg = Message::Text(String::from("hello"));
+        tx.send(msg.clone()).await.unwrap();
+        assert_eq!(rx.next().await.unwrap().unwrap(), msg);
+
+        tx.close().await.unwrap();
+    }
+}
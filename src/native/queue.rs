@@ -0,0 +1,475 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Bounded queue of the outgoing messages, between the sink and the background task
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+use futures_util::Stream;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::OpCode;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::OverflowPolicy;
+
+/// Outcome of a push
+pub(super) enum Push {
+    /// Queued
+    Queued,
+    /// Queued, dropping the oldest message
+    DroppedOldest(Message),
+    /// Not queued, the queue is full (and the policy drops the new messages)
+    DroppedNewest(Message),
+    /// Not queued, the queue is full
+    Full(Message),
+    /// Not queued, the receiver is gone
+    Closed(Message),
+}
+
+#[derive(Default)]
+struct State {
+    messages: VecDeque<Message>,
+    sender_closed: bool,
+    /// Sender dropped without closing
+    sender_dropped: bool,
+    receiver_closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Sender waiting for space
+    sender: AtomicWaker,
+    /// Receiver waiting for messages
+    receiver: AtomicWaker,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => e.into_inner(),
+        }
+    }
+}
+
+/// Create the queue (with at least 1 slot)
+pub(super) fn channel(capacity: usize, policy: OverflowPolicy) -> (Sender, Receiver) {
+    let shared: Arc<Shared> = Arc::new(Shared {
+        state: Mutex::new(State::default()),
+        capacity: capacity.max(1),
+        policy,
+        sender: AtomicWaker::new(),
+        receiver: AtomicWaker::new(),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub(super) struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    /// Wait for space in the queue (only with [`OverflowPolicy::Block`])
+    ///
+    /// Returns `false` if the receiver is gone.
+    pub(super) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<bool> {
+        if let Some(open) = self.ready() {
+            return Poll::Ready(open);
+        }
+
+        self.shared.sender.register(cx.waker());
+
+        // Check again: the receiver may have made space before the registration
+        match self.ready() {
+            Some(open) => Poll::Ready(open),
+            None => Poll::Pending,
+        }
+    }
+
+    fn ready(&self) -> Option<bool> {
+        let state = self.shared.lock();
+
+        if state.receiver_closed {
+            return Some(false);
+        }
+
+        if self.shared.policy != OverflowPolicy::Block
+            || state.messages.len() < self.shared.capacity
+        {
+            return Some(true);
+        }
+
+        None
+    }
+
+    /// Push a message, applying the overflow policy if the queue is full
+    ///
+    /// The policy applies only to the data messages: the control frames (i.e. the close frame) are always queued,
+    /// even beyond the capacity, and never dropped.
+    pub(super) fn push(&self, msg: Message) -> Push {
+        let push: Push = {
+            let mut state = self.shared.lock();
+
+            if state.receiver_closed || state.sender_closed {
+                return Push::Closed(msg);
+            }
+
+            if state.messages.len() < self.shared.capacity || is_control(&msg) {
+                state.messages.push_back(msg);
+                Push::Queued
+            } else {
+                match self.shared.policy {
+                    OverflowPolicy::Block | OverflowPolicy::Error => return Push::Full(msg),
+                    OverflowPolicy::DropNewest => return Push::DroppedNewest(msg),
+                    OverflowPolicy::DropOldest => {
+                        let oldest: Option<Message> = state
+                            .messages
+                            .iter()
+                            .position(|msg| !is_control(msg))
+                            .and_then(|index| state.messages.remove(index));
+                        state.messages.push_back(msg);
+                        match oldest {
+                            Some(oldest) => Push::DroppedOldest(oldest),
+                            None => Push::Queued,
+                        }
+                    }
+                }
+            }
+        };
+
+        self.shared.receiver.wake();
+        push
+    }
+
+    /// Check if the receiver is gone
+    #[inline]
+    pub(super) fn is_closed(&self) -> bool {
+        self.shared.lock().receiver_closed
+    }
+
+    /// Close the queue: the receiver gets the queued messages and then the end of the stream
+    pub(super) fn close(&self) {
+        self.shared.lock().sender_closed = true;
+        self.shared.receiver.wake();
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.sender_dropped = !state.sender_closed;
+        state.sender_closed = true;
+        drop(state);
+        self.shared.receiver.wake();
+    }
+}
+
+/// Check if the message is a control frame (close, ping or pong)
+fn is_control(msg: &Message) -> bool {
+    match msg {
+        Message::Text(..) | Message::Binary(..) => false,
+        Message::Ping(..) | Message::Pong(..) | Message::Close(..) => true,
+        Message::Frame(frame) => matches!(frame.header().opcode, OpCode::Control(..)),
+    }
+}
+
+pub(super) struct Receiver {
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    /// Check if no message is queued
+    #[inline]
+    pub(super) fn is_empty(&self) -> bool {
+        self.shared.lock().messages.is_empty()
+    }
+
+    /// Check if the sender has been dropped without closing the queue
+    #[inline]
+    pub(super) fn is_dropped(&self) -> bool {
+        self.shared.lock().sender_dropped
+    }
+
+    fn pop(&self) -> Option<Option<Message>> {
+        let mut state = self.shared.lock();
+        match state.messages.pop_front() {
+            Some(msg) => Some(Some(msg)),
+            None if state.sender_closed => Some(None),
+            None => None,
+        }
+    }
+}
+
+impl Stream for Receiver {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(msg) = self.pop() {
+            self.shared.sender.wake();
+            return Poll::Ready(msg);
+        }
+
+        self.shared.receiver.register(cx.waker());
+
+        // Check again: the sender may have pushed before the registration
+        match self.pop() {
+            Some(msg) => {
+                self.shared.sender.wake();
+                Poll::Ready(msg)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_closed = true;
+        self.shared.sender.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::future;
+    use futures_util::task::noop_waker_ref;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio::time;
+    use url::Url;
+
+    use super::*;
+    use crate::{CloseCode, ConnectionMode, ConnectionOptions, Error, TrySendError};
+
+    const POLICIES: [OverflowPolicy; 4] = [
+        OverflowPolicy::Block,
+        OverflowPolicy::DropNewest,
+        OverflowPolicy::DropOldest,
+        OverflowPolicy::Error,
+    ];
+
+    fn text(text: &str) -> Message {
+        Message::Text(String::from(text))
+    }
+
+    /// Close the queue and get the queued messages
+    async fn drain(tx: Sender, mut rx: Receiver) -> Vec<Message> {
+        drop(tx);
+        let mut messages: Vec<Message> = Vec::new();
+        while let Some(msg) = rx.next().await {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_full_queue() {
+        for policy in POLICIES {
+            // Never polled: the writer is stalled
+            let (tx, rx) = channel(2, policy);
+
+            assert!(matches!(tx.push(text("1")), Push::Queued));
+            assert!(matches!(tx.push(text("2")), Push::Queued));
+
+            let expected: Vec<Message> = match (policy, tx.push(text("3"))) {
+                (OverflowPolicy::Block | OverflowPolicy::Error, Push::Full(msg)) => {
+                    assert_eq!(msg, text("3"));
+                    vec![text("1"), text("2")]
+                }
+                (OverflowPolicy::DropNewest, Push::DroppedNewest(msg)) => {
+                    assert_eq!(msg, text("3"));
+                    vec![text("1"), text("2")]
+                }
+                (OverflowPolicy::DropOldest, Push::DroppedOldest(msg)) => {
+                    assert_eq!(msg, text("1"));
+                    vec![text("2"), text("3")]
+                }
+                (policy, ..) => panic!("unexpected push outcome with {policy:?}"),
+            };
+
+            assert_eq!(drain(tx, rx).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_space() {
+        let (tx, mut rx) = channel(1, OverflowPolicy::Block);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        assert_eq!(tx.poll_ready(&mut cx), Poll::Ready(true));
+        assert!(matches!(tx.push(text("1")), Push::Queued));
+        assert_eq!(tx.poll_ready(&mut cx), Poll::Pending);
+
+        assert_eq!(rx.next().await, Some(text("1")));
+        assert_eq!(tx.poll_ready(&mut cx), Poll::Ready(true));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_sender() {
+        // Closed, then dropped
+        let (tx, mut rx) = channel(2, OverflowPolicy::Block);
+        assert!(matches!(tx.push(text("1")), Push::Queued));
+        tx.close();
+        drop(tx);
+        assert_eq!(rx.next().await, Some(text("1")));
+        assert_eq!(rx.next().await, None);
+        assert!(!rx.is_dropped());
+
+        // Dropped without closing
+        let (tx, mut rx) = channel(2, OverflowPolicy::Block);
+        assert!(matches!(tx.push(text("1")), Push::Queued));
+        drop(tx);
+        assert_eq!(rx.next().await, Some(text("1")));
+        assert_eq!(rx.next().await, None);
+        assert!(rx.is_dropped());
+    }
+
+    #[tokio::test]
+    async fn test_control_frames_never_dropped() {
+        for policy in POLICIES {
+            let (tx, rx) = channel(2, policy);
+
+            assert!(matches!(tx.push(text("1")), Push::Queued));
+            assert!(matches!(tx.push(text("2")), Push::Queued));
+
+            // Beyond the capacity
+            assert!(matches!(tx.push(Message::Ping(Vec::new())), Push::Queued));
+            assert!(matches!(tx.push(Message::Close(None)), Push::Queued));
+
+            // The oldest data message is dropped, not the control frames
+            let expected: Vec<Message> = match policy {
+                OverflowPolicy::DropOldest => {
+                    assert!(
+                        matches!(tx.push(text("3")), Push::DroppedOldest(msg) if msg == text("1"))
+                    );
+                    assert!(
+                        matches!(tx.push(text("4")), Push::DroppedOldest(msg) if msg == text("2"))
+                    );
+                    assert!(
+                        matches!(tx.push(text("5")), Push::DroppedOldest(msg) if msg == text("3"))
+                    );
+                    vec![
+                        Message::Ping(Vec::new()),
+                        Message::Close(None),
+                        text("4"),
+                        text("5"),
+                    ]
+                }
+                _ => {
+                    assert!(!matches!(tx.push(text("3")), Push::Queued));
+                    vec![
+                        text("1"),
+                        text("2"),
+                        Message::Ping(Vec::new()),
+                        Message::Close(None),
+                    ]
+                }
+            };
+
+            assert_eq!(drain(tx, rx).await, expected);
+        }
+    }
+
+    /// Server sending `fill` messages, then echoing the received ones
+    async fn fill_server(fill: usize) -> Url {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: Url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            for i in 0..fill {
+                ws.feed(text(&format!("fill {i}"))).await.unwrap();
+            }
+            ws.flush().await.unwrap();
+
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_text() && ws.send(msg).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn test_stalled_writer() {
+        const FILL: usize = 8;
+
+        for policy in POLICIES {
+            let url: Url = fill_server(FILL).await;
+            let opts = ConnectionOptions::new()
+                .send_buffer(2)
+                .send_buffer_overflow(policy)
+                .receive_buffer(1);
+            let (mut tx, mut rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+                .await
+                .unwrap();
+
+            // The stream isn't polled: the task stops when the receive buffer is full, stalling the writes
+            time::sleep(Duration::from_millis(100)).await;
+
+            tx.try_send(text("1")).unwrap();
+            tx.try_send(text("2")).unwrap();
+
+            let expected: Vec<Message> = match (policy, tx.try_send(text("3"))) {
+                (OverflowPolicy::Block | OverflowPolicy::Error, Err(TrySendError::Full(..))) => {
+                    vec![text("1"), text("2")]
+                }
+                (OverflowPolicy::DropNewest, Ok(())) => vec![text("1"), text("2")],
+                (OverflowPolicy::DropOldest, Ok(())) => vec![text("2"), text("3")],
+                (policy, res) => panic!("unexpected result with {policy:?}: {res:?}"),
+            };
+
+            match policy {
+                OverflowPolicy::Block => {
+                    let send = time::timeout(Duration::from_millis(100), tx.send(text("3")));
+                    assert!(send.await.is_err(), "send didn't wait for space");
+                }
+                OverflowPolicy::Error => {
+                    let res = tx.send(text("3")).await;
+                    assert!(matches!(res, Err(Error::SendBufferFull)));
+                }
+                OverflowPolicy::DropNewest | OverflowPolicy::DropOldest => {
+                    assert_eq!(tx.stats().messages_dropped, 1);
+                }
+            }
+
+            // The close frame isn't dropped, even if the buffer is full
+            let drain = async {
+                let mut messages: Vec<Message> = Vec::new();
+                while let Some(Ok(msg)) = rx.next().await {
+                    messages.push(msg);
+                }
+                messages
+            };
+            let close = time::timeout(
+                Duration::from_secs(5),
+                tx.close_with(CloseCode::Normal, None),
+            );
+            let (res, mut messages) =
+                time::timeout(Duration::from_secs(10), future::join(close, drain))
+                    .await
+                    .expect("close not completed");
+            assert!(matches!(res, Ok(Ok(()))), "close failed with {policy:?}");
+
+            assert!(matches!(messages.pop(), Some(Message::Close(..))));
+            assert_eq!(messages.split_off(FILL), expected, "with {policy:?}");
+        }
+    }
+}
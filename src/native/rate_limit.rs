@@ -0,0 +1,207 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Outbound rate limit (token bucket)
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use super::runtime::{self, Instant, Sleep};
+use crate::ConnectionOptions;
+
+/// Token bucket
+#[derive(Debug)]
+struct Bucket {
+    /// Tokens added per second
+    rate: f64,
+    /// Max tokens
+    burst: f64,
+    /// Available tokens (negative if in debt)
+    tokens: f64,
+    /// Last refill
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: u64, now: Instant) -> Self {
+        let burst: f64 = burst.max(1) as f64;
+        Self {
+            rate: rate.max(1) as f64,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed: f64 = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Time to wait until there are at least `needed` tokens
+    fn delay(&mut self, needed: f64, now: Instant) -> Option<Duration> {
+        self.refill(now);
+
+        if self.tokens >= needed {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64((needed - self.tokens) / self.rate))
+    }
+
+    #[inline]
+    fn take(&mut self, tokens: f64) {
+        self.tokens -= tokens;
+    }
+}
+
+/// Rate limiter of the outgoing messages
+///
+/// A message is sent when there is a message token and the byte bucket isn't in debt:
+/// the size of the message is known only after the sink is ready, so a message bigger than
+/// the available bytes is sent anyway and the following ones wait for the debt to be refilled.
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl RateLimiter {
+    /// Get the rate limiter, or `None` if the options don't set a rate limit
+    pub(super) fn from_options(opts: &ConnectionOptions) -> Option<Self> {
+        if opts.message_rate.is_none() && opts.byte_rate.is_none() {
+            return None;
+        }
+
+        let now: Instant = Instant::now();
+        Some(Self {
+            messages: opts
+                .message_rate
+                .map(|(rate, burst)| Bucket::new(rate.into(), burst.into(), now)),
+            bytes: opts
+                .byte_rate
+                .map(|(rate, burst)| Bucket::new(rate, burst, now)),
+            sleep: None,
+        })
+    }
+
+    /// Time to wait before sending the next message
+    fn delay(&mut self) -> Option<Duration> {
+        let now: Instant = Instant::now();
+        let messages = self.messages.as_mut().and_then(|b| b.delay(1.0, now));
+        let bytes = self.bytes.as_mut().and_then(|b| b.delay(0.0, now));
+        messages.max(bytes)
+    }
+
+    /// Check if a message can be sent now
+    #[inline]
+    pub(super) fn is_ready(&mut self) -> bool {
+        self.delay().is_none()
+    }
+
+    /// Wait until a message can be sent
+    pub(super) fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                match sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => self.sleep = None,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            match self.delay() {
+                Some(delay) => self.sleep = Some(Box::pin(runtime::sleep(delay))),
+                None => return Poll::Ready(()),
+            }
+        }
+    }
+
+    /// Take the tokens of a sent message
+    pub(super) fn consume(&mut self, len: usize) {
+        if let Some(messages) = &mut self.messages {
+            messages.take(1.0);
+        }
+
+        if let Some(bytes) = &mut self.bytes {
+            bytes.take(len as f64);
+        }
+    }
+}
+
+// The paused clock is the tokio one
+#[cfg(all(test, feature = "runtime-tokio"))]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::time;
+    use url::Url;
+
+    use super::*;
+    use crate::native::{Sink, TrySendError};
+    use crate::WsMessage;
+
+    /// Connect over an in-memory pipe, to a server reading all the messages
+    async fn connect(opts: ConnectionOptions) -> Sink {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut ws = tokio_tungstenite::accept_async(server).await.unwrap();
+            while let Some(Ok(..)) = ws.next().await {}
+        });
+
+        let url: Url = Url::parse("ws://localhost").unwrap();
+        let (tx, _rx) = crate::connect_on(client, &url, opts).await.unwrap();
+        tx
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_messages() {
+        let mut tx = connect(ConnectionOptions::new().rate_limit_messages(10, 1)).await;
+
+        // The first message uses the burst, the next 10 wait 100 ms each
+        let start: Instant = Instant::now();
+        for i in 0..11 {
+            tx.send(WsMessage::Text(i.to_string())).await.unwrap();
+        }
+        let elapsed: Duration = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(990), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(1100), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_bytes() {
+        let mut tx = connect(ConnectionOptions::new().rate_limit_bytes(1000, 1000)).await;
+
+        // 2500 bytes: the third message puts the bucket in debt 500 bytes, paid back before each of the next 2
+        let start: Instant = Instant::now();
+        for _ in 0..5 {
+            tx.send(WsMessage::Binary(vec![0; 500])).await.unwrap();
+        }
+        let elapsed: Duration = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(990), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(1100), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_try_send_rate_limited() {
+        let opts = ConnectionOptions::new()
+            .send_buffer(16)
+            .rate_limit_messages(1, 1);
+        let mut tx = connect(opts).await;
+
+        tx.try_send(WsMessage::Text(String::from("first"))).unwrap();
+
+        // The burst is used: the message is given back
+        let msg = WsMessage::Text(String::from("second"));
+        match tx.try_send(msg.clone()) {
+            Err(TrySendError::RateLimited(m)) => assert_eq!(m, msg),
+            res => panic!("unexpected result: {res:?}"),
+        }
+
+        // Refilled
+        time::advance(Duration::from_secs(1)).await;
+        tx.try_send(msg).unwrap();
+    }
+}
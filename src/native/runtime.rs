@@ -0,0 +1,247 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Async runtime
+//!
+//! The runtime-specific pieces (tasks, timers, TCP and DNS), selected with the `runtime-tokio` (default)
+//! or the `runtime-async-std` feature. If both are enabled, tokio is used.
+//!
+//! The rest of the crate is runtime-agnostic: the tokio traits and sync primitives don't need a tokio runtime.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[cfg(feature = "tor")]
+use arti_client::DataStream;
+
+#[cfg(not(any(feature = "runtime-tokio", feature = "runtime-async-std")))]
+compile_error!("enable one of the `runtime-tokio` or `runtime-async-std` features");
+
+#[cfg(feature = "runtime-tokio")]
+mod imp {
+    use super::*;
+
+    pub(crate) use tokio::net::TcpStream;
+    pub(crate) use tokio::time::{timeout, Instant, Sleep};
+
+    #[inline]
+    pub(crate) fn spawn<F>(future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        tokio::spawn(future);
+    }
+
+    #[inline]
+    pub(crate) fn sleep_until(deadline: Instant) -> Sleep {
+        tokio::time::sleep_until(deadline)
+    }
+
+    pub(crate) async fn lookup_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+
+    pub(crate) async fn connect(
+        socket: socket2::Socket,
+        addr: SocketAddr,
+    ) -> io::Result<TcpStream> {
+        let socket = tokio::net::TcpSocket::from_std_stream(socket.into());
+        socket.connect(addr).await
+    }
+
+    #[inline]
+    pub(crate) fn peer_addr(stream: &TcpStream) -> io::Result<SocketAddr> {
+        stream.peer_addr()
+    }
+
+    #[inline]
+    pub(crate) fn local_addr(stream: &TcpStream) -> io::Result<SocketAddr> {
+        stream.local_addr()
+    }
+
+    /// Tor stream, as used by the websocket
+    #[cfg(feature = "tor")]
+    pub(crate) type TorStream = DataStream;
+
+    #[cfg(feature = "tor")]
+    #[inline]
+    pub(crate) fn tor_stream(stream: DataStream) -> TorStream {
+        stream
+    }
+}
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+mod imp {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use async_io::{Async, Timer};
+    use async_std::net::ToSocketAddrs;
+    use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+
+    use super::*;
+
+    pub(crate) use std::time::Instant;
+
+    /// TCP stream, with the tokio IO traits
+    pub(crate) type TcpStream = Compat<async_std::net::TcpStream>;
+    pub(crate) use async_std::future::timeout;
+
+    #[inline]
+    pub(crate) fn spawn<F>(future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        async_std::task::spawn(future);
+    }
+
+    /// Future returned by [`sleep_until`]
+    #[derive(Debug)]
+    pub(crate) struct Sleep(Timer);
+
+    impl Future for Sleep {
+        type Output = ();
+
+        #[inline]
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            Pin::new(&mut self.0).poll(cx).map(|_| ())
+        }
+    }
+
+    #[inline]
+    pub(crate) fn sleep_until(deadline: Instant) -> Sleep {
+        Sleep(Timer::at(deadline))
+    }
+
+    pub(crate) async fn lookup_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs().await?.collect())
+    }
+
+    pub(crate) async fn connect(
+        socket: socket2::Socket,
+        addr: SocketAddr,
+    ) -> io::Result<TcpStream> {
+        match socket.connect(&addr.into()) {
+            Ok(()) => {}
+            #[cfg(unix)]
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+
+        // Writable once connected (or failed)
+        let stream: Async<std::net::TcpStream> = Async::new(socket.into())?;
+        stream.writable().await?;
+
+        if let Some(e) = stream.get_ref().take_error()? {
+            return Err(e);
+        }
+
+        let stream: std::net::TcpStream = stream.into_inner()?;
+        Ok(async_std::net::TcpStream::from(stream).compat())
+    }
+
+    #[inline]
+    pub(crate) fn peer_addr(stream: &TcpStream) -> io::Result<SocketAddr> {
+        stream.get_ref().peer_addr()
+    }
+
+    #[inline]
+    pub(crate) fn local_addr(stream: &TcpStream) -> io::Result<SocketAddr> {
+        stream.get_ref().local_addr()
+    }
+
+    /// Tor stream, as used by the websocket
+    #[cfg(feature = "tor")]
+    pub(crate) type TorStream = Compat<DataStream>;
+
+    #[cfg(feature = "tor")]
+    #[inline]
+    pub(crate) fn tor_stream(stream: DataStream) -> TorStream {
+        stream.compat()
+    }
+}
+
+pub(crate) use self::imp::*;
+
+/// Sleep for `duration`
+#[inline]
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Ticker, with the first tick after `period`
+///
+/// A late tick delays the next ones (the next tick is `period` after the late one).
+#[derive(Debug)]
+pub(crate) struct Interval {
+    period: Duration,
+    next: Instant,
+}
+
+impl Interval {
+    #[inline]
+    pub(crate) fn new(period: Duration) -> Self {
+        Self {
+            period,
+            next: Instant::now() + period,
+        }
+    }
+
+    /// Wait for the next tick
+    ///
+    /// Cancel safe: if dropped before completing, the tick isn't consumed.
+    pub(crate) async fn tick(&mut self) {
+        sleep_until(self.next).await;
+        self.next = Instant::now() + self.period;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use url::Url;
+
+    use super::*;
+    use crate::test_util::EchoServer;
+    use crate::{ConnectionMode, ConnectionOptions, WsMessage};
+
+    /// Echo a message, with the keepalive running in the background task, and close
+    async fn echo(addr: SocketAddr) {
+        let url: Url = Url::parse(&format!("ws://{addr}")).unwrap();
+        let opts =
+            ConnectionOptions::new().keepalive(Duration::from_millis(50), Duration::from_secs(5));
+        let (mut tx, mut rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+        assert_eq!(tx.peer_addr(), Some(addr));
+
+        let msg = WsMessage::Text(String::from("hello"));
+        tx.send(msg.clone()).await.unwrap();
+        assert_eq!(rx.next().await.unwrap().unwrap(), msg);
+
+        // The pings are sent, and the pongs received, by the timers of the background task
+        sleep(Duration::from_millis(200)).await;
+        assert!(rx.last_pong().is_some());
+
+        tx.close().await.unwrap();
+    }
+
+    #[cfg(feature = "runtime-tokio")]
+    #[tokio::test]
+    async fn test_connect_tokio() {
+        let (addr, _server) = EchoServer::new().spawn().await;
+        echo(addr).await;
+    }
+
+    #[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+    #[test]
+    fn test_connect_async_std() {
+        // The echo server runs on its own tokio runtime, the client on async-std only
+        let server = tokio::runtime::Runtime::new().unwrap();
+        let (addr, _server) = server.block_on(EchoServer::new().spawn());
+        async_std::task::block_on(echo(addr));
+    }
+}
@@ -3,20 +3,35 @@
 
 //! Socks
 
-use std::net::SocketAddr;
-
-use tokio::net::TcpStream;
 use tokio_socks::tcp::Socks5Stream;
 use tokio_socks::IntoTargetAddr;
 
+use super::runtime::TcpStream;
+
 pub(crate) struct TcpSocks5Stream;
 
 impl TcpSocks5Stream {
     #[inline]
     pub async fn connect<'a>(
-        proxy: SocketAddr,
+        proxy: TcpStream,
+        dest: impl IntoTargetAddr<'a>,
+    ) -> Result<TcpStream, tokio_socks::Error> {
+        Ok(Socks5Stream::connect_with_socket(proxy, dest)
+            .await?
+            .into_inner())
+    }
+
+    #[inline]
+    pub async fn connect_with_password<'a>(
+        proxy: TcpStream,
         dest: impl IntoTargetAddr<'a>,
+        username: &str,
+        password: &str,
     ) -> Result<TcpStream, tokio_socks::Error> {
-        Ok(Socks5Stream::connect(proxy, dest).await?.into_inner())
+        Ok(
+            Socks5Stream::connect_with_password_and_socket(proxy, dest, username, password)
+                .await?
+                .into_inner(),
+        )
     }
 }
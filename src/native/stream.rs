@@ -1,92 +1,1373 @@
 // Copyright (c) 2022-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::io::Cursor;
+use std::net::SocketAddr;
 use std::ops::DerefMut;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-#[cfg(feature = "tor")]
-use arti_client::DataStream;
 use futures_util::stream::{SplitSink, SplitStream};
-use futures_util::{Sink as SinkTrait, Stream as StreamTrait};
-use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite::Message;
+use futures_util::{Sink as SinkTrait, SinkExt, Stream as StreamTrait, StreamExt};
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, watch, Notify};
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::HeaderMap;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::frame::{Frame, FrameHeader};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
-use super::error::Error;
+use super::error::{Error, TrySendError};
+use super::queue::{self, Push};
+use super::rate_limit::RateLimiter;
+#[cfg(feature = "tor")]
+use super::runtime::TorStream;
+use super::runtime::{self, TcpStream};
+use super::task::{self, Timers};
+use super::timings::ConnectTimings;
+use crate::batch::{self, SendBatchError};
+#[cfg(feature = "serde")]
+use crate::json::Json;
+use crate::options::DEFAULT_CLOSE_TIMEOUT;
+use crate::payload::{self, Payload};
+use crate::state::StateNotifier;
+use crate::stats::StatsCounter;
+use crate::{ConnectionOptions, ConnectionState, ConnectionStats, DisconnectReason};
 
 type WsStream<T> = WebSocketStream<MaybeTlsStream<T>>;
 
+/// Transport provided by the caller (see [`connect_on`](crate::connect_on))
+pub(super) trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// Max length of a close reason (RFC 6455, section 5.5)
+const MAX_CLOSE_REASON_LEN: usize = 123;
+
+/// Connection metadata, shared by the [`Sink`] and [`Stream`] halves
+#[derive(Debug)]
+pub(super) struct Metadata {
+    /// Connection id
+    pub(super) id: u64,
+    /// Headers of the handshake response
+    headers: HeaderMap,
+    /// Address of the remote peer
+    peer_addr: Option<SocketAddr>,
+    /// Local address
+    local_addr: Option<SocketAddr>,
+    /// Time taken by the connection phases
+    timings: ConnectTimings,
+    /// Last pong received by the keepalive task
+    last_pong: Mutex<Option<Instant>>,
+    /// Close frame received from the server
+    peer_close: watch::Sender<Option<CloseFrame<'static>>>,
+    /// How long to wait for the close frame of the server
+    pub(super) close_timeout: Duration,
+    /// Close frame answering an invalid frame of the server, sent when the sink is closed
+    violation: Mutex<Option<CloseFrame<'static>>>,
+    /// Connection state
+    pub(super) state: StateNotifier,
+    /// Traffic counters
+    pub(super) stats: StatsCounter,
+    /// Messages queued for the background task, not written yet
+    unsent: AtomicUsize,
+    /// Stop the background task, dropping the connection
+    pub(super) abort: Notify,
+    /// Timers of the background task
+    pub(super) timers: watch::Sender<Timers>,
+}
+
+impl Metadata {
+    pub(super) fn new(
+        id: u64,
+        headers: HeaderMap,
+        peer_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+        timings: ConnectTimings,
+    ) -> Self {
+        Self {
+            id,
+            headers,
+            peer_addr,
+            local_addr,
+            timings,
+            last_pong: Mutex::new(None),
+            peer_close: watch::Sender::new(None),
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            violation: Mutex::new(None),
+            state: StateNotifier::new(ConnectionState::Connected),
+            stats: StatsCounter::default(),
+            unsent: AtomicUsize::new(0),
+            abort: Notify::new(),
+            timers: watch::Sender::new(Timers::default()),
+        }
+    }
+
+    /// A queued message has been written by the background task (or failed to be)
+    #[inline]
+    pub(super) fn on_written(&self) {
+        self.unsent.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Push a message to the send buffer, applying the overflow policy
+    ///
+    /// Returns `false` if the message has been dropped.
+    pub(super) fn push(&self, tx: &queue::Sender, msg: Message) -> Result<bool, TrySendError> {
+        let len: usize = msg.len();
+
+        match tx.push(msg) {
+            Push::Queued => {}
+            Push::DroppedOldest(oldest) => {
+                tracing::trace!(
+                    id = self.id,
+                    len = oldest.len(),
+                    "Send buffer full, dropped the oldest message."
+                );
+                self.unsent.fetch_sub(1, Ordering::Relaxed);
+                self.stats.unqueued(oldest.len());
+            }
+            Push::DroppedNewest(msg) => {
+                tracing::trace!(
+                    id = self.id,
+                    len = msg.len(),
+                    "Send buffer full, dropped the new message."
+                );
+                self.stats.dropped();
+                return Ok(false);
+            }
+            Push::Full(msg) => return Err(TrySendError::Full(msg)),
+            Push::Closed(msg) => return Err(TrySendError::Closed(msg)),
+        }
+
+        self.unsent.fetch_add(1, Ordering::Relaxed);
+        self.stats.sent(len);
+        Ok(true)
+    }
+
+    /// Inspect a received message, to keep track of the close frame of the server
+    pub(super) fn on_message(&self, msg: &Message) {
+        tracing::trace!(
+            id = self.id,
+            len = msg.len(),
+            "Received {} frame.",
+            kind(msg)
+        );
+
+        if let Message::Close(frame) = msg {
+            // A close frame without payload means "no status received" (RFC 6455, section 7.1.5)
+            let frame: CloseFrame<'static> = frame.clone().unwrap_or(CloseFrame {
+                code: CloseCode::Status,
+                reason: "".into(),
+            });
+            self.peer_close.send_replace(Some(frame));
+            self.state.disconnected(DisconnectReason::Closed);
+        }
+    }
+
+    /// Wait for the end of the connection: close frame of the server, error or end of the stream
+    async fn closed(&self) {
+        // Subscribed before checking the state, to not miss the transition
+        let mut events = self.state.subscribe();
+
+        while self.state.is_connected() {
+            // Lagged: check the state again
+            if let Err(broadcast::error::RecvError::Closed) = events.recv().await {
+                return;
+            }
+        }
+    }
+
+    /// Update the state after a poll of the stream
+    pub(super) fn on_poll(&self, poll: &Poll<Option<Result<Message, Error>>>) {
+        match poll {
+            Poll::Ready(Some(Ok(msg))) => self.on_message(msg),
+            Poll::Ready(Some(Err(e))) => {
+                if let Some(frame) = violation_close_frame(e) {
+                    self.set_violation(Some(frame));
+                }
+
+                self.state
+                    .disconnected(DisconnectReason::Error(e.to_string()));
+            }
+            Poll::Ready(None) => self.state.disconnected(DisconnectReason::Closed),
+            Poll::Pending => {}
+        }
+    }
+
+    /// Subprotocol negotiated during the handshake
+    fn protocol(&self) -> Option<&str> {
+        self.headers
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|protocol| !protocol.is_empty())
+    }
+
+    fn last_pong(&self) -> Option<Instant> {
+        match self.last_pong.lock() {
+            Ok(last_pong) => *last_pong,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    pub(super) fn set_last_pong(&self, instant: Instant) {
+        match self.last_pong.lock() {
+            Ok(mut last_pong) => *last_pong = Some(instant),
+            Err(e) => *e.into_inner() = Some(instant),
+        }
+    }
+
+    fn take_violation(&self) -> Option<CloseFrame<'static>> {
+        match self.violation.lock() {
+            Ok(mut violation) => violation.take(),
+            Err(e) => e.into_inner().take(),
+        }
+    }
+
+    fn set_violation(&self, frame: Option<CloseFrame<'static>>) {
+        match self.violation.lock() {
+            Ok(mut violation) => *violation = frame,
+            Err(e) => *e.into_inner() = frame,
+        }
+    }
+}
+
+/// Parse a raw frame, unmasking the payload (if masked)
+fn parse_raw_frame(raw: &[u8]) -> Result<Frame, Error> {
+    let mut cursor: Cursor<&[u8]> = Cursor::new(raw);
+    let (mut header, len) = FrameHeader::parse(&mut cursor)?.ok_or(Error::InvalidFrame)?;
+
+    let payload: &[u8] = &raw[cursor.position() as usize..];
+
+    if payload.len() as u64 != len {
+        return Err(Error::InvalidFrame);
+    }
+
+    let mut payload: Vec<u8> = payload.to_vec();
+
+    if let Some(mask) = header.mask.take() {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+
+    Ok(Frame::from_payload(header, payload))
+}
+
+/// Close frame for the invalid frames received from the server, if any (RFC 6455, section 7.4.1)
+///
+/// `tungstenite` returns the error without closing the connection.
+pub(super) fn violation_close_frame(e: &Error) -> Option<CloseFrame<'static>> {
+    match e {
+        // Invalid frame payload data
+        Error::InvalidUtf8 => Some(CloseFrame {
+            code: CloseCode::Invalid,
+            reason: "invalid UTF-8".into(),
+        }),
+        // Protocol error (i.e. a control frame with a payload longer than 125 bytes)
+        Error::Ws(e) => match e.as_ref() {
+            WsError::Protocol(
+                e @ (ProtocolError::ControlFrameTooBig
+                | ProtocolError::FragmentedControlFrame
+                | ProtocolError::UnknownControlFrameType(..)
+                | ProtocolError::UnknownDataFrameType(..)
+                | ProtocolError::NonZeroReservedBits
+                | ProtocolError::MaskedFrameFromServer
+                | ProtocolError::UnexpectedContinueFrame
+                | ProtocolError::ExpectedFragment(..)
+                | ProtocolError::InvalidCloseSequence),
+            ) => Some(CloseFrame {
+                code: CloseCode::Protocol,
+                reason: e.to_string().into(),
+            }),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Close the connection, with the close frame answering an invalid frame of the server (if any)
+fn poll_close_split<S>(
+    mut sink: Pin<&mut S>,
+    metadata: &Metadata,
+    cx: &mut Context<'_>,
+) -> Poll<Result<(), Error>>
+where
+    S: SinkTrait<Message, Error = WsError>,
+{
+    if let Some(frame) = metadata.take_violation() {
+        match sink.as_mut().poll_ready(cx) {
+            Poll::Ready(Ok(())) => sink.as_mut().start_send(Message::Close(Some(frame)))?,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+            Poll::Pending => {
+                metadata.set_violation(Some(frame));
+                return Poll::Pending;
+            }
+        }
+    }
+
+    sink.poll_close(cx).map_err(Into::into)
+}
+
+/// Kind of the message, for the tracing events
+fn kind(msg: &Message) -> &'static str {
+    match msg {
+        Message::Text(..) => "text",
+        Message::Binary(..) => "binary",
+        Message::Ping(..) => "ping",
+        Message::Pong(..) => "pong",
+        Message::Close(..) => "close",
+        Message::Frame(..) => "raw",
+    }
+}
+
 pub enum WebSocket {
     Std(WsStream<TcpStream>),
     #[cfg(feature = "tor")]
-    Tor(WsStream<DataStream>),
+    Tor(WsStream<TorStream>),
+    Custom(WsStream<Box<dyn Transport>>),
 }
 
-pub enum Sink {
+impl WebSocket {
+    /// TCP connection (through the TLS layer, if any)
+    ///
+    /// There is no meaningful TCP connection for a connection over tor, or the TCP connection isn't known (custom transport).
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Std(stream) => match stream.get_ref() {
+                MaybeTlsStream::Plain(s) => Some(s),
+                #[cfg(feature = "rustls")]
+                MaybeTlsStream::Rustls(s) => Some(s.get_ref().0),
+                #[cfg(feature = "native-tls")]
+                MaybeTlsStream::NativeTls(s) => Some(s.get_ref().get_ref().get_ref()),
+                _ => None,
+            },
+            #[cfg(feature = "tor")]
+            Self::Tor(..) => None,
+            Self::Custom(..) => None,
+        }
+    }
+
+    /// Address of the remote peer of the TCP connection
+    #[inline]
+    pub(super) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.tcp_stream().and_then(|s| runtime::peer_addr(s).ok())
+    }
+
+    /// Local address of the TCP connection
+    #[inline]
+    pub(super) fn local_addr(&self) -> Option<SocketAddr> {
+        self.tcp_stream().and_then(|s| runtime::local_addr(s).ok())
+    }
+
+    /// Split the connection
+    ///
+    /// If the options require it (keepalive, idle timeout or send buffer), the connection is moved into the background task.
+    pub(super) fn split(self, metadata: Metadata, opts: &ConnectionOptions) -> (Sink, Stream) {
+        let metadata: Arc<Metadata> = Arc::new(Metadata {
+            close_timeout: opts.close_timeout,
+            ..metadata
+        });
+        let limiter: Option<RateLimiter> = RateLimiter::from_options(opts);
+
+        if let Some(config) = task::Config::from_options(opts) {
+            let (tx, rx) = match self {
+                Self::Std(stream) => task::spawn(stream, config, metadata.clone()),
+                #[cfg(feature = "tor")]
+                Self::Tor(stream) => task::spawn(stream, config, metadata.clone()),
+                Self::Custom(stream) => task::spawn(stream, config, metadata.clone()),
+            };
+
+            return (
+                Sink {
+                    inner: SinkInner::Task(tx),
+                    limiter,
+                    metadata: metadata.clone(),
+                },
+                Stream {
+                    inner: StreamInner::Task(rx),
+                    metadata,
+                },
+            );
+        }
+
+        let (tx, rx) = match self {
+            Self::Std(stream) => {
+                let (tx, rx) = stream.split();
+                (SinkInner::Std(tx), StreamInner::Std(rx))
+            }
+            #[cfg(feature = "tor")]
+            Self::Tor(stream) => {
+                let (tx, rx) = stream.split();
+                (SinkInner::Tor(tx), StreamInner::Tor(rx))
+            }
+            Self::Custom(stream) => {
+                let (tx, rx) = stream.split();
+                (SinkInner::Custom(tx), StreamInner::Custom(rx))
+            }
+        };
+
+        (
+            Sink {
+                inner: tx,
+                limiter,
+                metadata: metadata.clone(),
+            },
+            Stream {
+                inner: rx,
+                metadata,
+            },
+        )
+    }
+}
+
+/// The background task is gone, so the connection is closed
+#[inline]
+fn closed() -> Error {
+    Error::Ws(Box::new(WsError::AlreadyClosed))
+}
+
+enum SinkInner {
     Std(SplitSink<WsStream<TcpStream>, Message>),
     #[cfg(feature = "tor")]
-    Tor(SplitSink<WsStream<DataStream>, Message>),
+    Tor(SplitSink<WsStream<TorStream>, Message>),
+    Custom(SplitSink<WsStream<Box<dyn Transport>>, Message>),
+    Task(queue::Sender),
+}
+
+/// Sending half of a WebSocket connection
+///
+/// Independent of the [`Stream`] half, so it can be moved into another task.
+/// Closing it starts the closing handshake: the [`Stream`] keeps returning the queued messages until
+/// the server confirms the close. Dropping it without closing leaves the [`Stream`] untouched (the background task,
+/// if any, keeps the connection and its keepalive): the connection is closed when both halves are dropped.
+///
+/// Each message is written as a single frame (the outgoing messages aren't fragmented) and sending
+/// requires `&mut self`, so the messages are never interleaved. To send from several tasks, share it behind
+/// an async mutex (i.e. `tokio::sync::Mutex`): each `send` completes before the next one starts.
+///
+/// The frames are always masked with a random key, as required for the clients (RFC 6455, section 5.3):
+/// the key is generated by `tungstenite`, which allows neither disabling the masking nor choosing the key,
+/// so the bytes on the wire can't be reproduced (i.e. for conformance tests).
+pub struct Sink {
+    inner: SinkInner,
+    limiter: Option<RateLimiter>,
+    metadata: Arc<Metadata>,
+}
+
+impl Sink {
+    /// Get the connection id, unique within the process (the `id` field of the tracing events)
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.metadata.id
+    }
+
+    /// Get a snapshot of the connection traffic
+    #[inline]
+    pub fn stats(&self) -> ConnectionStats {
+        self.metadata.stats.snapshot()
+    }
+
+    /// Check if the connection is still open, without locking
+    ///
+    /// It becomes `false` when a close frame, an error (i.e. I/O error, keepalive timeout) or the end of the stream
+    /// is observed: by the background task, if any (keepalive, idle timeout or send buffer), otherwise
+    /// only when the [`Stream`] is polled.
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.metadata.state.is_connected()
+    }
+
+    /// Get the current connection state
+    #[inline]
+    pub fn state(&self) -> ConnectionState {
+        self.metadata.state.get()
+    }
+
+    /// Subscribe to the connection state transitions
+    #[inline]
+    pub fn state_events(&self) -> broadcast::Receiver<ConnectionState> {
+        self.metadata.state.subscribe()
+    }
+
+    /// Get the subprotocol negotiated during the handshake
+    #[inline]
+    pub fn protocol(&self) -> Option<&str> {
+        self.metadata.protocol()
+    }
+
+    /// Get the headers of the handshake response (`101 Switching Protocols`)
+    #[inline]
+    pub fn response_headers(&self) -> &HeaderMap {
+        &self.metadata.headers
+    }
+
+    /// Get the address of the remote peer
+    ///
+    /// In proxy mode, it's the address of the proxy. Always `None` in tor mode.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.metadata.peer_addr
+    }
+
+    /// Get the local address of the connection (i.e. the port assigned by the OS)
+    ///
+    /// Reflects the address set with [`ConnectionOptions::bind_addr`], if any.
+    /// In proxy mode, it's the local address of the connection to the proxy. Always `None` in tor mode.
+    #[inline]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.metadata.local_addr
+    }
+
+    /// Get the time taken by each phase of the connection (DNS, TCP, TLS, handshake, ...)
+    #[inline]
+    pub fn connect_timings(&self) -> ConnectTimings {
+        self.metadata.timings
+    }
+
+    /// Get when the last keepalive pong was received
+    ///
+    /// Always `None` if keepalive is disabled.
+    #[inline]
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.metadata.last_pong()
+    }
+
+    /// Change the keepalive of the connection (`None` to disable it)
+    ///
+    /// The next ping is sent after the new interval, a ping already waiting for the pong waits for the new timeout.
+    /// Requires the background task (keepalive, idle timeout or send buffer enabled in the options),
+    /// otherwise [`Error::NoBackgroundTask`] is returned. A zero interval or timeout returns [`Error::ZeroDuration`].
+    pub fn set_keepalive(&self, keepalive: Option<(Duration, Duration)>) -> Result<(), Error> {
+        if let Some((interval, timeout)) = keepalive {
+            if interval.is_zero() || timeout.is_zero() {
+                return Err(Error::ZeroDuration);
+            }
+        }
+
+        self.update_timers(|timers| timers.keepalive = keepalive)
+    }
+
+    /// Change the idle timeout of the connection (`None` to disable it)
+    ///
+    /// The new timeout counts from the last activity. Like [`Sink::set_keepalive`], it requires the background task.
+    /// A zero timeout returns [`Error::ZeroDuration`].
+    pub fn set_idle_timeout(&self, idle_timeout: Option<(Duration, bool)>) -> Result<(), Error> {
+        if let Some((timeout, ..)) = idle_timeout {
+            if timeout.is_zero() {
+                return Err(Error::ZeroDuration);
+            }
+        }
+
+        self.update_timers(|timers| timers.idle_timeout = idle_timeout)
+    }
+
+    fn update_timers<F>(&self, update: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Timers),
+    {
+        if !matches!(self.inner, SinkInner::Task(..)) {
+            return Err(Error::NoBackgroundTask);
+        }
+
+        // No receiver if the background task is gone
+        if self.metadata.timers.receiver_count() == 0 {
+            return Err(closed());
+        }
+
+        self.metadata.timers.send_modify(update);
+        Ok(())
+    }
+
+    /// Send a pre-serialized frame (header and payload), bypassing the message encoder (i.e. in a relay)
+    ///
+    /// The frame is parsed only to apply the mask required for the clients (a masked frame is unmasked first):
+    /// the payload isn't re-encoded. Returns [`Error::InvalidFrame`] if `frame` isn't exactly one complete frame.
+    ///
+    /// Nothing else is checked, so the caller must uphold the protocol invariants, or the server may drop the connection:
+    /// - the reserved bits are unset (no extension is negotiated) and the opcode is a known one;
+    /// - the control frames are final, with a payload of up to 125 bytes;
+    /// - the payload of a text message is valid UTF-8;
+    /// - the frames of a fragmented message aren't interleaved with the messages sent with `send`;
+    /// - no close frame is sent raw: use `close` or [`Sink::close_with`], so the closing handshake is tracked.
+    pub async fn send_raw_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        let frame: Frame = parse_raw_frame(frame)?;
+        self.send(Message::Frame(frame)).await
+    }
+
+    /// Close the connection with a status code and a reason
+    ///
+    /// Send the close frame and wait for the one of the server, or the end of the connection
+    /// (up to 10 secs, see [`ConnectionOptions::close_timeout`]).
+    /// The close frame of the server is received by the [`Stream`], so it must be polled
+    /// (unless keepalive, the idle timeout or the send buffer is enabled).
+    ///
+    /// The reason can't be longer than 123 bytes. To close without code, use `SinkExt::close`.
+    pub async fn close_with(
+        &mut self,
+        code: CloseCode,
+        reason: Option<String>,
+    ) -> Result<(), Error> {
+        let reason: String = reason.unwrap_or_default();
+
+        if reason.len() > MAX_CLOSE_REASON_LEN {
+            return Err(Error::CloseReasonTooLong);
+        }
+
+        // Already closed by the server
+        if self.metadata.peer_close.borrow().is_some() {
+            return Ok(());
+        }
+
+        tracing::debug!(id = self.metadata.id, %code, "Closing connection.");
+
+        let frame: CloseFrame<'static> = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        self.send(Message::Close(Some(frame))).await?;
+
+        runtime::timeout(self.metadata.close_timeout, self.metadata.closed())
+            .await
+            .map_err(|_| Error::Timeout)
+    }
+
+    /// Close the connection after the queued messages have been sent, waiting up to `timeout`
+    ///
+    /// The close frame is sent after the messages queued in the send buffer (if any), so when the server
+    /// confirms the close, all of them have been received. The close frame of the server is received by
+    /// the background task or by the [`Stream`] (that must be polled, if the connection isn't owned by the task).
+    ///
+    /// On expiry, the background task (if any) is stopped, dropping the connection, and [`Error::PartialClose`]
+    /// is returned with the number of the messages not written (the close frame included).
+    pub async fn drain_and_close(&mut self, timeout: Duration) -> Result<(), Error> {
+        tracing::debug!(id = self.metadata.id, "Draining and closing connection.");
+
+        let res = runtime::timeout(timeout, async {
+            // Already closed by the server: the queued messages can't be sent anymore
+            if self.metadata.peer_close.borrow().is_none() {
+                self.send(Message::Close(None)).await?;
+                self.metadata.closed().await;
+            }
+
+            Ok(())
+        })
+        .await;
+
+        match res {
+            Ok(res) => res,
+            Err(_) => {
+                self.metadata.abort.notify_one();
+                Err(Error::PartialClose {
+                    unsent: self.metadata.unsent.load(Ordering::Relaxed),
+                })
+            }
+        }
+    }
+
+    /// Queue a message, without waiting
+    ///
+    /// Returns [`TrySendError::Full`] if the send buffer is full, so the message can be dropped or retried later
+    /// (unless a drop policy is set, see [`ConnectionOptions::send_buffer_overflow`](crate::ConnectionOptions::send_buffer_overflow)).
+    /// The buffer is drained by a background task: it requires [`ConnectionOptions::send_buffer`](crate::ConnectionOptions::send_buffer)
+    /// (or keepalive, or the idle timeout), otherwise [`TrySendError::NotBuffered`] is returned.
+    /// If the rate limit is exceeded, [`TrySendError::RateLimited`] is returned.
+    pub fn try_send(&mut self, msg: Message) -> Result<(), TrySendError> {
+        match &mut self.inner {
+            SinkInner::Task(tx) => {
+                if let Some(limiter) = &mut self.limiter {
+                    if !limiter.is_ready() {
+                        return Err(TrySendError::RateLimited(msg));
+                    }
+                }
+
+                let len: usize = msg.len();
+
+                tracing::trace!(id = self.metadata.id, len, "Queueing {} frame.", kind(&msg));
+
+                if self.metadata.push(tx, msg)? {
+                    if let Some(limiter) = &mut self.limiter {
+                        limiter.consume(len);
+                    }
+                }
+
+                Ok(())
+            }
+            _ => Err(TrySendError::NotBuffered(msg)),
+        }
+    }
+
+    /// Send all the messages, flushing once
+    ///
+    /// Faster than sending them one by one, as the frames are written together.
+    /// Stops on the first error, reporting which message failed.
+    #[inline]
+    pub async fn send_batch<I>(&mut self, msgs: I) -> Result<(), SendBatchError>
+    where
+        I: IntoIterator<Item = Message>,
+    {
+        batch::send_batch(self, msgs).await
+    }
 }
 
 impl SinkTrait<Message> for Sink {
     type Error = Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match self.deref_mut() {
-            Self::Std(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+        let this = self.deref_mut();
+
+        if let Some(limiter) = &mut this.limiter {
+            if limiter.poll_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        match &mut this.inner {
+            SinkInner::Std(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
             #[cfg(feature = "tor")]
-            Self::Tor(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            SinkInner::Tor(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            SinkInner::Custom(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            SinkInner::Task(s) => match s.poll_ready(cx) {
+                Poll::Ready(true) => Poll::Ready(Ok(())),
+                Poll::Ready(false) => Poll::Ready(Err(closed())),
+                Poll::Pending => Poll::Pending,
+            },
         }
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
-        match self.deref_mut() {
-            Self::Std(s) => Pin::new(s).start_send(item).map_err(Into::into),
+        let this = self.deref_mut();
+        let len: usize = item.len();
+
+        tracing::trace!(id = this.metadata.id, len, "Sending {} frame.", kind(&item));
+
+        match &mut this.inner {
+            SinkInner::Std(s) => Pin::new(s).start_send(item)?,
             #[cfg(feature = "tor")]
-            Self::Tor(s) => Pin::new(s).start_send(item).map_err(Into::into),
+            SinkInner::Tor(s) => Pin::new(s).start_send(item)?,
+            SinkInner::Custom(s) => Pin::new(s).start_send(item)?,
+            SinkInner::Task(s) => {
+                let queued: bool = this.metadata.push(s, item).map_err(|e| match e {
+                    TrySendError::Full(..) => Error::SendBufferFull,
+                    _ => closed(),
+                })?;
+
+                if queued {
+                    if let Some(limiter) = &mut this.limiter {
+                        limiter.consume(len);
+                    }
+                }
+
+                return Ok(());
+            }
         }
+
+        if let Some(limiter) = &mut this.limiter {
+            limiter.consume(len);
+        }
+
+        this.metadata.stats.sent(len);
+
+        Ok(())
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match self.deref_mut() {
-            Self::Std(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+        match &mut self.deref_mut().inner {
+            SinkInner::Std(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
             #[cfg(feature = "tor")]
-            Self::Tor(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            SinkInner::Tor(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            SinkInner::Custom(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            // The background task writes the queued messages as soon as possible
+            SinkInner::Task(s) if s.is_closed() => Poll::Ready(Err(closed())),
+            SinkInner::Task(..) => Poll::Ready(Ok(())),
         }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match self.deref_mut() {
-            Self::Std(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+        let this = self.deref_mut();
+        match &mut this.inner {
+            SinkInner::Std(s) => poll_close_split(Pin::new(s), &this.metadata, cx),
             #[cfg(feature = "tor")]
-            Self::Tor(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            SinkInner::Tor(s) => poll_close_split(Pin::new(s), &this.metadata, cx),
+            SinkInner::Custom(s) => poll_close_split(Pin::new(s), &this.metadata, cx),
+            SinkInner::Task(s) => {
+                s.close();
+                Poll::Ready(Ok(()))
+            }
         }
     }
 }
 
-pub enum Stream {
+enum StreamInner {
     Std(SplitStream<WsStream<TcpStream>>),
     #[cfg(feature = "tor")]
-    Tor(SplitStream<WsStream<DataStream>>),
+    Tor(SplitStream<WsStream<TorStream>>),
+    Custom(SplitStream<WsStream<Box<dyn Transport>>>),
+    Task(task::Incoming),
+}
+
+/// Receiving half of a WebSocket connection
+///
+/// Independent of the [`Sink`] half, so it can be moved into another task.
+/// It ends when the connection is closed, by either the [`Sink`] or the server: the close frame of the server
+/// (with its code and reason) is returned as [`Message::Close`] before the end of the stream. Likewise, sending a
+/// [`Message::Close`] with the [`Sink`] starts the closing handshake, and the reply of the server is returned here.
+///
+/// The ping frames of the server are returned as [`Message::Ping`], but they are also answered
+/// automatically (it can't be disabled): the pong is written with the next read or write.
+/// A pong sent with the [`Sink`] before then replaces the automatic one (i.e. to reply with a custom payload),
+/// unless the connection is owned by the background task.
+///
+/// The fragmented messages are reassembled before being returned: the whole message is buffered in memory,
+/// up to [`ConnectionOptions::max_message_size`](crate::ConnectionOptions::max_message_size). The frames of a
+/// message can't be received one by one (`tungstenite` doesn't expose them), so lower the limit, rather than
+/// disabling it, if the server may send huge messages.
+///
+/// An invalid frame of the server (i.e. a ping with a payload longer than 125 bytes) ends the stream with
+/// the protocol error, and the connection is closed with the code `1002` (`1007` for the invalid UTF-8)
+/// immediately by the background task, if any. Otherwise the stream can't write to the connection, so it
+/// stays open until the [`Sink`] is closed (`SinkExt::close` sends the close frame with the code):
+/// after the error, close the sink rather than dropping it, which drops the connection without the close frame.
+pub struct Stream {
+    inner: StreamInner,
+    metadata: Arc<Metadata>,
+}
+
+impl Stream {
+    /// Get the connection id, unique within the process (the `id` field of the tracing events)
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.metadata.id
+    }
+
+    /// Get a snapshot of the connection traffic
+    #[inline]
+    pub fn stats(&self) -> ConnectionStats {
+        self.metadata.stats.snapshot()
+    }
+
+    /// Check if the connection is still open, without locking
+    ///
+    /// It becomes `false` when a close frame, an error (i.e. I/O error, keepalive timeout) or the end of the stream
+    /// is observed: by the background task, if any (keepalive, idle timeout or send buffer), otherwise
+    /// only when the [`Stream`] is polled.
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.metadata.state.is_connected()
+    }
+
+    /// Get the current connection state
+    #[inline]
+    pub fn state(&self) -> ConnectionState {
+        self.metadata.state.get()
+    }
+
+    /// Subscribe to the connection state transitions
+    #[inline]
+    pub fn state_events(&self) -> broadcast::Receiver<ConnectionState> {
+        self.metadata.state.subscribe()
+    }
+
+    /// Get the subprotocol negotiated during the handshake
+    #[inline]
+    pub fn protocol(&self) -> Option<&str> {
+        self.metadata.protocol()
+    }
+
+    /// Get the headers of the handshake response (`101 Switching Protocols`)
+    #[inline]
+    pub fn response_headers(&self) -> &HeaderMap {
+        &self.metadata.headers
+    }
+
+    /// Get the address of the remote peer
+    ///
+    /// In proxy mode, it's the address of the proxy. Always `None` in tor mode.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.metadata.peer_addr
+    }
+
+    /// Get the local address of the connection (i.e. the port assigned by the OS)
+    ///
+    /// Reflects the address set with [`ConnectionOptions::bind_addr`], if any.
+    /// In proxy mode, it's the local address of the connection to the proxy. Always `None` in tor mode.
+    #[inline]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.metadata.local_addr
+    }
+
+    /// Get the time taken by each phase of the connection (DNS, TCP, TLS, handshake, ...)
+    #[inline]
+    pub fn connect_timings(&self) -> ConnectTimings {
+        self.metadata.timings
+    }
+
+    /// Get when the last keepalive pong was received
+    ///
+    /// Always `None` if keepalive is disabled.
+    #[inline]
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.metadata.last_pong()
+    }
+
+    /// Get the close frame received from the server, if any
+    ///
+    /// A close frame without status code is reported as [`CloseCode::Status`] (1005).
+    #[inline]
+    pub fn close_frame(&self) -> Option<CloseFrame<'static>> {
+        self.metadata.peer_close.borrow().clone()
+    }
+
+    /// Receive the next message, waiting up to `timeout`
+    ///
+    /// Returns [`Error::Timeout`] on expiry: the connection is NOT closed and no message is lost,
+    /// so the call can be retried.
+    /// Returns `Ok(None)` if the connection is closed (also while waiting).
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message>, Error> {
+        match runtime::timeout(timeout, self.next()).await {
+            Ok(Some(res)) => res.map(Some),
+            Ok(None) => Ok(None),
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+
+    /// Receive the next data message, with the binary payload as [`Bytes`](crate::Bytes)
+    ///
+    /// The control messages are skipped. See [`Payload`] for the savings over `Vec<u8>`.
+    #[inline]
+    pub async fn next_payload(&mut self) -> Option<Result<Payload, Error>> {
+        payload::next_payload(self).await
+    }
+
+    /// Deserialize the text and binary messages from JSON, skipping the control messages
+    ///
+    /// A malformed message yields an [`Error::Json`] without ending the stream.
+    #[cfg(feature = "serde")]
+    #[inline]
+    pub fn json<T>(self) -> Json<Self, T>
+    where
+        T: DeserializeOwned,
+    {
+        Json::new(self)
+    }
 }
 
 impl StreamTrait for Stream {
     type Item = Result<Message, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.deref_mut() {
-            Self::Std(s) => Pin::new(s).poll_next(cx).map_err(Into::into),
+        let this = self.deref_mut();
+        let poll = match &mut this.inner {
+            StreamInner::Std(s) => {
+                let poll = Pin::new(s).poll_next(cx).map_err(Into::into);
+                this.metadata.on_poll(&poll);
+                poll
+            }
             #[cfg(feature = "tor")]
-            Self::Tor(s) => Pin::new(s).poll_next(cx).map_err(Into::into),
+            StreamInner::Tor(s) => {
+                let poll = Pin::new(s).poll_next(cx).map_err(Into::into);
+                this.metadata.on_poll(&poll);
+                poll
+            }
+            StreamInner::Custom(s) => {
+                let poll = Pin::new(s).poll_next(cx).map_err(Into::into);
+                this.metadata.on_poll(&poll);
+                poll
+            }
+            // Already inspected by the background task
+            StreamInner::Task(s) => Pin::new(s).poll_next(cx),
+        };
+
+        if let Poll::Ready(Some(Ok(msg))) = &poll {
+            this.metadata.stats.received(msg.len());
         }
+
+        poll
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        match self {
-            Self::Std(s) => s.size_hint(),
+        match &self.inner {
+            StreamInner::Std(s) => s.size_hint(),
             #[cfg(feature = "tor")]
-            Self::Tor(s) => s.size_hint(),
+            StreamInner::Tor(s) => s.size_hint(),
+            StreamInner::Custom(s) => s.size_hint(),
+            StreamInner::Task(s) => s.size_hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::future;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+    use url::Url;
+
+    use super::*;
+    use crate::test_util::{raw, EchoServer};
+    use crate::{ConnectionMode, ConnectionOptions};
+
+    const MESSAGES: usize = 100;
+
+    /// Receive the invalid `frame`, returning the error and the code of the close frame written in answer
+    async fn invalid_frame(frame: Vec<u8>, opts: ConnectionOptions) -> (Error, Option<u16>) {
+        let with_task: bool = task::Config::from_options(&opts).is_some();
+        let (url, mut closed) = raw::server(frame).await;
+        let (mut tx, mut rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+
+        let e: Error = match rx.next().await {
+            Some(Err(e)) => e,
+            res => panic!("unexpected result: {res:?}"),
+        };
+        assert!(matches!(tx.state(), ConnectionState::Disconnected(..)));
+
+        if with_task {
+            // Closed by the background task
+            assert!(rx.next().await.is_none());
+        } else {
+            // Without the background task, the stream can't write: the connection stays open until the sink
+            // is closed, sending the close frame with the code
+            let res = time::timeout(Duration::from_millis(100), &mut closed).await;
+            assert!(res.is_err(), "closed before the sink");
+            tx.close().await.unwrap();
+            drop((tx, rx));
+        }
+
+        let received: Vec<u8> = raw::wait_closed(closed).await;
+        (e, raw::close_code(&received))
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8() {
+        for opts in [
+            ConnectionOptions::new(),
+            ConnectionOptions::new().send_buffer(8),
+        ] {
+            let frame: Vec<u8> = raw::frame(0x1, &[0xf0, 0x28, 0x8c, 0x28]);
+            let (e, code) = invalid_frame(frame, opts).await;
+            assert!(matches!(e, Error::InvalidUtf8));
+            // Invalid frame payload data
+            assert_eq!(code, Some(1007));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_too_big() {
+        for opts in [
+            ConnectionOptions::new(),
+            ConnectionOptions::new().send_buffer(8),
+        ] {
+            let (e, code) = invalid_frame(raw::frame(0x9, &[0; 200]), opts).await;
+            assert!(
+                matches!(e, Error::Ws(ref e) if matches!(**e, WsError::Protocol(ProtocolError::ControlFrameTooBig)))
+            );
+            // Protocol error
+            assert_eq!(code, Some(1002));
+        }
+    }
+
+    /// Masked final frame
+    fn masked_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+        let mut frame: Vec<u8> = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        frame
+    }
+
+    #[test]
+    fn test_parse_raw_frame() {
+        for raw in [raw::frame(0x1, b"hello"), masked_frame(0x1, b"hello")] {
+            let frame: Frame = parse_raw_frame(&raw).unwrap();
+            assert_eq!(frame.header().opcode, OpCode::Data(Data::Text));
+            assert!(frame.header().is_final);
+            // Masked again by the sink
+            assert!(frame.header().mask.is_none());
+            assert_eq!(frame.payload(), b"hello");
+        }
+    }
+
+    #[test]
+    fn test_parse_raw_frame_invalid() {
+        let frame: Vec<u8> = raw::frame(0x2, &[1, 2, 3, 4, 5]);
+
+        // Truncated header and payload
+        for truncated in [&frame[..1], &frame[..frame.len() - 2], &[]] {
+            assert!(matches!(
+                parse_raw_frame(truncated),
+                Err(Error::InvalidFrame)
+            ));
+        }
+
+        // Trailing bytes (i.e. the start of another frame)
+        let mut trailing: Vec<u8> = frame.clone();
+        trailing.extend_from_slice(&raw::frame(0x2, &[6]));
+        assert!(matches!(
+            parse_raw_frame(&trailing),
+            Err(Error::InvalidFrame)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_raw_frame() {
+        let (addr, _server) = EchoServer::new().spawn().await;
+        let url: Url = Url::parse(&format!("ws://{addr}")).unwrap();
+        let (mut tx, mut rx) =
+            crate::connect(&url, &ConnectionMode::Direct, ConnectionOptions::new())
+                .await
+                .unwrap();
+
+        for raw in [raw::frame(0x1, b"hello"), masked_frame(0x1, b"world")] {
+            tx.send_raw_frame(&raw).await.unwrap();
+        }
+        assert_eq!(
+            rx.next().await.unwrap().unwrap(),
+            Message::Text(String::from("hello"))
+        );
+        assert_eq!(
+            rx.next().await.unwrap().unwrap(),
+            Message::Text(String::from("world"))
+        );
+
+        // Not sent
+        let res = tx.send_raw_frame(&raw::frame(0x1, b"hello")[..3]).await;
+        assert!(matches!(res, Err(Error::InvalidFrame)));
+        assert!(tx.state().is_connected());
+    }
+
+    /// Writer and reader in separate tasks: dropping the sink leaves the stream untouched
+    async fn split(opts: ConnectionOptions) -> Stream {
+        let (addr, _server) = EchoServer::new().spawn().await;
+        let url: Url = Url::parse(&format!("ws://{addr}")).unwrap();
+        let (mut tx, mut rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+
+        let writer = tokio::spawn(async move {
+            for i in 0..MESSAGES {
+                tx.send(Message::Text(i.to_string())).await.unwrap();
+            }
+            // Dropped without closing
+        });
+
+        let reader = tokio::spawn(async move {
+            for i in 0..MESSAGES {
+                let msg: Message = rx.next().await.unwrap().unwrap();
+                assert_eq!(msg, Message::Text(i.to_string()));
+            }
+            rx
+        });
+
+        writer.await.unwrap();
+        let mut rx: Stream = reader.await.unwrap();
+
+        // No close frame sent: the echo server would have answered it, ending the stream
+        let res = time::timeout(Duration::from_millis(300), async {
+            loop {
+                match rx.next().await {
+                    // Keepalive
+                    Some(Ok(Message::Pong(..))) => continue,
+                    msg => return msg,
+                }
+            }
+        })
+        .await;
+        assert!(res.is_err(), "{res:?}");
+        assert!(rx.is_connected());
+
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_split() {
+        split(ConnectionOptions::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_split_with_task() {
+        let opts =
+            ConnectionOptions::new().keepalive(Duration::from_millis(50), Duration::from_secs(5));
+        let rx: Stream = split(opts).await;
+
+        // The keepalive is still running
+        let last_pong: Instant = rx.last_pong().unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(rx.last_pong().unwrap() > last_pong);
+    }
+
+    /// Close with a code while reading the stream, returning the last message and the close result
+    async fn close_with(
+        mut tx: Sink,
+        mut rx: Stream,
+    ) -> (Result<(), Error>, Option<Message>, Stream) {
+        let reader = async {
+            let mut last: Option<Message> = None;
+            while let Some(Ok(msg)) = rx.next().await {
+                last = Some(msg);
+            }
+            last
+        };
+
+        let (res, last) = future::join(
+            tx.close_with(CloseCode::Normal, Some(String::from("bye"))),
+            reader,
+        )
+        .await;
+        (res, last, rx)
+    }
+
+    #[tokio::test]
+    async fn test_close_handshake() {
+        let (addr, _server) = EchoServer::new().spawn().await;
+        let url: Url = Url::parse(&format!("ws://{addr}")).unwrap();
+
+        for opts in [
+            ConnectionOptions::new(),
+            ConnectionOptions::new().send_buffer(8),
+        ] {
+            let (tx, rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+                .await
+                .unwrap();
+
+            let (res, last, rx) = close_with(tx, rx).await;
+            res.unwrap();
+
+            // The close frame echoed by the server
+            let frame: CloseFrame<'static> = CloseFrame {
+                code: CloseCode::Normal,
+                reason: "bye".into(),
+            };
+            assert_eq!(last, Some(Message::Close(Some(frame.clone()))));
+            assert_eq!(rx.close_frame(), Some(frame));
+            assert_eq!(
+                rx.state(),
+                ConnectionState::Disconnected(DisconnectReason::Closed)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_without_answer() {
+        for opts in [
+            ConnectionOptions::new(),
+            ConnectionOptions::new().send_buffer(8),
+        ] {
+            // The server drops the connection when receiving the close frame, without answering it
+            let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url: Url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+            tokio::spawn(async move {
+                let mut stream: TcpStream = raw::accept(&listener).await;
+                let _ = stream.read(&mut [0u8; 1024]).await;
+            });
+
+            let (tx, rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+                .await
+                .unwrap();
+
+            // Completed at the end of the connection, not when the close timeout expires
+            let (res, last, rx) = time::timeout(Duration::from_secs(5), close_with(tx, rx))
+                .await
+                .expect("close not completed");
+            res.unwrap();
+            assert!(last.is_none());
+            assert!(rx.close_frame().is_none());
+            assert!(!rx.is_connected());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_timeout() {
+        for opts in [
+            ConnectionOptions::new(),
+            ConnectionOptions::new().send_buffer(8),
+        ] {
+            // The server never answers the close frame
+            let (url, _closed) = raw::server(Vec::new()).await;
+            let opts = opts.close_timeout(Duration::from_millis(100));
+            let (mut tx, _rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+                .await
+                .unwrap();
+
+            let res = time::timeout(
+                Duration::from_secs(5),
+                tx.close_with(CloseCode::Normal, None),
+            )
+            .await
+            .expect("close timeout not applied");
+            assert!(matches!(res, Err(Error::Timeout)));
+        }
+    }
+
+    /// Large message of a writer, filled with a pattern to detect the interleaved frames
+    fn large_message(writer: u8, seq: u8) -> Message {
+        let mut payload: Vec<u8> = vec![writer ^ seq; 256 << 10];
+        payload[0] = writer;
+        payload[1] = seq;
+        Message::Binary(payload)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_large_messages() {
+        const WRITERS: u8 = 4;
+        const MESSAGES: u8 = 16;
+
+        let (addr, _server) = EchoServer::new().spawn().await;
+        let url: Url = Url::parse(&format!("ws://{addr}")).unwrap();
+
+        for opts in [
+            ConnectionOptions::new(),
+            ConnectionOptions::new().send_buffer(8),
+        ] {
+            let (tx, mut rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+                .await
+                .unwrap();
+            let tx: Arc<tokio::sync::Mutex<Sink>> = Arc::new(tokio::sync::Mutex::new(tx));
+
+            let writers: Vec<_> = (0..WRITERS)
+                .map(|writer| {
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        for seq in 0..MESSAGES {
+                            let msg: Message = large_message(writer, seq);
+                            tx.lock().await.send(msg).await.unwrap();
+                        }
+                    })
+                })
+                .collect();
+
+            // The messages of each writer are received whole and in order
+            let mut next: [u8; WRITERS as usize] = [0; WRITERS as usize];
+            for _ in 0..(WRITERS as usize * MESSAGES as usize) {
+                let msg: Message = time::timeout(Duration::from_secs(30), rx.next())
+                    .await
+                    .expect("message not received")
+                    .unwrap()
+                    .unwrap();
+                let payload: Vec<u8> = msg.into_data();
+
+                let (writer, seq) = (payload[0], payload[1]);
+                assert_eq!(payload, large_message(writer, seq).into_data());
+                assert_eq!(next[writer as usize], seq);
+                next[writer as usize] += 1;
+            }
+
+            for writer in writers {
+                writer.await.unwrap();
+            }
+            assert_eq!(next, [MESSAGES; WRITERS as usize]);
         }
     }
 }
@@ -0,0 +1,79 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection target
+
+use std::net::SocketAddr;
+
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use url::{Host, Url};
+
+use super::error::Error;
+
+/// Connection target, parsed from the URL
+#[derive(Debug, Clone)]
+pub(super) struct Target<'a> {
+    /// Host
+    pub(super) host: Host<&'a str>,
+    /// Host, as in the URL (IPv6 addresses in brackets)
+    pub(super) host_str: &'a str,
+    /// Port, explicit or default of the scheme (`ws`: 80, `wss`: 443)
+    pub(super) port: u16,
+}
+
+impl<'a> Target<'a> {
+    /// Parse the `ws` or `wss` URL
+    ///
+    /// Fails if the scheme isn't supported (or TLS is required but no TLS feature is enabled).
+    /// The host is already validated by the [`Url`] parser: a malformed IPv6 literal (e.g. `wss://[2001:db8::1/`)
+    /// is rejected with [`ParseError::InvalidIpv6Address`](url::ParseError::InvalidIpv6Address).
+    pub(super) fn parse(url: &'a Url) -> Result<Self, Error> {
+        match url.scheme() {
+            "ws" => {}
+            "wss" if cfg!(any(feature = "rustls", feature = "native-tls")) => {}
+            "wss" => {
+                return Err(Error::Ws(Box::new(WsError::Url(
+                    UrlError::TlsFeatureNotEnabled,
+                ))))
+            }
+            _ => return Err(Error::unsupported_scheme()),
+        }
+
+        Ok(Self {
+            host: url.host().ok_or_else(Error::empty_host)?,
+            host_str: url.host_str().ok_or_else(Error::empty_host)?,
+            port: url
+                .port_or_known_default()
+                .ok_or_else(Error::invalid_port)?,
+        })
+    }
+
+    /// Get the socket address if the host is an IP address, to dial it without DNS resolution
+    pub(super) fn socket_addr(&self) -> Option<SocketAddr> {
+        match self.host {
+            Host::Domain(..) => None,
+            Host::Ipv4(ip) => Some(SocketAddr::new(ip.into(), self.port)),
+            Host::Ipv6(ip) => Some(SocketAddr::new(ip.into(), self.port)),
+        }
+    }
+
+    /// Get the host without the brackets of the IPv6 addresses
+    #[cfg(feature = "tor")]
+    #[inline]
+    pub(super) fn host_unbracketed(&self) -> &'a str {
+        self.host_str
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(self.host_str)
+    }
+
+    /// Check if the host is an onion address
+    #[inline]
+    pub(super) fn is_onion(&self) -> bool {
+        match self.host {
+            Host::Domain(domain) => domain.trim_end_matches('.').ends_with(".onion"),
+            _ => false,
+        }
+    }
+}
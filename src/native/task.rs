@@ -0,0 +1,612 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Background task owning the connection (ping/pong keepalive, idle timeout and send buffer)
+
+use std::collections::VecDeque;
+use std::future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_channel::mpsc::{self, Receiver, Sender};
+use futures_channel::oneshot;
+use futures_util::{Sink as SinkTrait, SinkExt, Stream as StreamTrait, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::Instrument;
+
+use super::error::Error;
+use super::queue;
+use super::runtime::{self, Instant, Interval};
+use super::stream::{self, Metadata};
+use crate::{ConnectionOptions, DisconnectReason, OverflowPolicy};
+
+/// Default buffer size of the channels between the connection halves and the task
+pub(super) const CHANNEL_SIZE: usize = 32;
+/// Prefix of the payload of the keepalive pings, followed by the sequence number
+const PING_PREFIX: &[u8] = b"keepalive:";
+
+/// Timers of the background task, adjustable while connected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct Timers {
+    /// Ping interval and pong timeout
+    pub(super) keepalive: Option<(Duration, Duration)>,
+    /// Max time without activity, and if the sent messages count as activity
+    pub(super) idle_timeout: Option<(Duration, bool)>,
+}
+
+/// Config of the background task
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Config {
+    /// Initial timers
+    timers: Timers,
+    /// Max unanswered keepalive pings, if sent without waiting for the pongs
+    max_unanswered_pings: Option<usize>,
+    /// Capacity of the outgoing queue
+    send_buffer: usize,
+    /// What to do when the outgoing queue is full
+    overflow: OverflowPolicy,
+    /// Capacity of the incoming channel
+    receive_buffer: usize,
+}
+
+impl Config {
+    /// Get the task config, or `None` if the options don't require the task
+    pub(super) fn from_options(opts: &ConnectionOptions) -> Option<Self> {
+        if opts.keepalive.is_none() && opts.idle_timeout.is_none() && opts.send_buffer.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            timers: Timers {
+                keepalive: opts.keepalive,
+                idle_timeout: opts.idle_timeout,
+            },
+            max_unanswered_pings: opts.max_unanswered_pings,
+            send_buffer: opts.send_buffer.unwrap_or(CHANNEL_SIZE),
+            overflow: opts.send_buffer_overflow,
+            receive_buffer: opts.receive_buffer.unwrap_or(CHANNEL_SIZE).max(1),
+        })
+    }
+}
+
+/// Receiving half of the incoming channel
+///
+/// Wakes the task when dropped: the channel doesn't notify the sender.
+pub(super) struct Incoming {
+    // Dropped before `_dropped`, so the channel is closed when the task wakes up
+    rx: Receiver<Result<Message, Error>>,
+    _dropped: oneshot::Sender<()>,
+}
+
+impl StreamTrait for Incoming {
+    type Item = Result<Message, Error>;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rx.size_hint()
+    }
+}
+
+/// Spawn the task owning the connection: it forwards the messages from/to the channels,
+/// sends the pings (if keepalive is enabled) and closes the connection when idle (if enabled).
+pub(super) fn spawn<T>(
+    ws: WebSocketStream<MaybeTlsStream<T>>,
+    config: Config,
+    metadata: Arc<Metadata>,
+) -> (queue::Sender, Incoming)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (outgoing_tx, outgoing_rx) = queue::channel(config.send_buffer, config.overflow);
+    // The channel has an extra slot for each sender
+    let (incoming_tx, incoming_rx) = mpsc::channel(config.receive_buffer - 1);
+    let (dropped_tx, dropped_rx) = oneshot::channel();
+    // Subscribed before spawning, so the timers can be changed immediately
+    metadata.timers.send_replace(config.timers);
+    let timers_rx: watch::Receiver<Timers> = metadata.timers.subscribe();
+    let span = tracing::debug_span!("connection", id = metadata.id);
+    runtime::spawn(
+        run(
+            ws,
+            outgoing_rx,
+            incoming_tx,
+            dropped_rx,
+            timers_rx,
+            config,
+            metadata,
+        )
+        .instrument(span),
+    );
+    let incoming = Incoming {
+        rx: incoming_rx,
+        _dropped: dropped_tx,
+    };
+    (outgoing_tx, incoming)
+}
+
+async fn run<T>(
+    ws: WebSocketStream<MaybeTlsStream<T>>,
+    mut outgoing: queue::Receiver,
+    mut incoming: Sender<Result<Message, Error>>,
+    mut stream_dropped: oneshot::Receiver<()>,
+    mut timers_rx: watch::Receiver<Timers>,
+    config: Config,
+    metadata: Arc<Metadata>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // Changed by `Sink::set_keepalive` and `Sink::set_idle_timeout`
+    let Timers {
+        mut keepalive,
+        mut idle_timeout,
+    } = config.timers;
+
+    let mut ticker: Option<Interval> = keepalive.map(|(interval, ..)| new_ticker(interval));
+    let mut timeout: Duration = keepalive.map(|(.., timeout)| timeout).unwrap_or_default();
+
+    let max_unanswered: Option<usize> = config.max_unanswered_pings;
+    let mut pings: Pings = Pings::default();
+    let mut sink_closed: bool = false;
+    let mut sink_dropped: bool = false;
+    // Forwarded to the stream once the connection is closed
+    let mut failure: Option<Error> = None;
+
+    // When the last frame was received (or sent, if enabled)
+    let mut last_activity: Instant = Instant::now();
+    let (mut idle, mut idle_on_send): (Duration, bool) = idle_timeout.unwrap_or_default();
+
+    // Split, so the connection is still read while a write is blocked (i.e. the server is writing too)
+    let (ws, mut ws_rx) = ws.split();
+    let mut writer: Writer<_> = Writer::new(ws);
+
+    loop {
+        // Pong timeout of the oldest unanswered ping
+        let deadline: Instant = pings.oldest().unwrap_or_else(Instant::now) + timeout;
+
+        tokio::select! {
+            res = writer.write_next((!sink_closed && !sink_dropped).then_some(&mut outgoing)),
+                if writer.is_busy() || (!sink_closed && !sink_dropped) => match res {
+                Ok(Written::Queued) => {
+                    metadata.on_written();
+
+                    if idle_on_send {
+                        last_activity = Instant::now();
+                    }
+                }
+                Ok(Written::Control) => {}
+                Ok(Written::End) if outgoing.is_dropped() => {
+                    // The sink has been dropped without closing: the connection is kept for the stream
+                    // (i.e. the keepalive pings are still sent), like without the background task.
+                    sink_dropped = true;
+                }
+                Ok(Written::End) => {
+                    // The sink has been closed: start the closing handshake,
+                    // but keep reading so the stream can drain the remaining messages.
+                    sink_closed = true;
+                    writer.control = Some(Message::Close(None));
+                }
+                Err(e) => {
+                    failure = fail(&metadata, e);
+                    break;
+                }
+            },
+            msg = ws_rx.next() => match msg {
+                Some(Ok(msg)) => {
+                    metadata.on_message(&msg);
+                    last_activity = Instant::now();
+
+                    if let Message::Pong(payload) = &msg {
+                        pings.answered(payload);
+                        metadata.stats.set_unanswered_pings(pings.len());
+                        metadata.set_last_pong(std::time::Instant::now());
+                    }
+
+                    // If the stream has been dropped, keep the task running for the sink
+                    let _ = incoming.send(Ok(msg)).await;
+                }
+                Some(Err(e)) => {
+                    let e: Error = e.into();
+
+                    // The invalid frames are not closed by `tungstenite`
+                    if let Some(frame) = stream::violation_close_frame(&e) {
+                        let _ = runtime::timeout(metadata.close_timeout, writer.close(Some(frame))).await;
+                    }
+
+                    failure = fail(&metadata, e);
+                    break;
+                }
+                None => break,
+            },
+            _ = tick(&mut ticker),
+                if !sink_closed && writer.control.is_none() && (max_unanswered.is_some() || pings.is_empty()) => {
+                if let Some(max) = max_unanswered {
+                    if pings.len() >= max {
+                        tracing::debug!("{max} pings unanswered, closing connection.");
+                        failure = fail(&metadata, Error::KeepaliveTimeout);
+                        let _ = runtime::timeout(timeout, writer.close(None)).await;
+                        break;
+                    }
+                }
+
+                tracing::trace!("Sending keepalive ping.");
+
+                let payload: Vec<u8> = pings.next();
+                metadata.stats.set_unanswered_pings(pings.len());
+
+                // Written before the queued messages
+                writer.control = Some(Message::Ping(payload));
+            }
+            _ = runtime::sleep_until(deadline), if !pings.is_empty() => {
+                tracing::debug!("No pong received within {timeout:?}, closing connection.");
+                failure = fail(&metadata, Error::KeepaliveTimeout);
+                let _ = runtime::timeout(timeout, writer.close(None)).await;
+                break;
+            }
+            // Only wakes the task up, for the check below
+            _ = &mut stream_dropped, if !incoming.is_closed() => {}
+            _ = metadata.abort.notified() => {
+                tracing::debug!("Connection aborted.");
+                break;
+            }
+            Ok(()) = timers_rx.changed() => {
+                let timers: Timers = *timers_rx.borrow_and_update();
+
+                if timers.keepalive != keepalive {
+                    tracing::debug!(keepalive = ?timers.keepalive, "Keepalive changed.");
+
+                    // The next ping is sent after the new interval
+                    ticker = timers.keepalive.map(|(interval, ..)| new_ticker(interval));
+                    timeout = timers.keepalive.map(|(.., timeout)| timeout).unwrap_or_default();
+
+                    // The unanswered pings (if any) wait for the new timeout
+                    if timers.keepalive.is_none() {
+                        pings.clear();
+                        metadata.stats.set_unanswered_pings(0);
+                    }
+                }
+
+                if timers.idle_timeout != idle_timeout {
+                    tracing::debug!(idle_timeout = ?timers.idle_timeout, "Idle timeout changed.");
+                    (idle, idle_on_send) = timers.idle_timeout.unwrap_or_default();
+                }
+
+                Timers { keepalive, idle_timeout } = timers;
+            }
+            _ = runtime::sleep_until(last_activity + idle), if idle_timeout.is_some() => {
+                tracing::debug!("No activity within {idle:?}, closing connection.");
+                failure = fail(&metadata, Error::IdleTimeout);
+                let _ = runtime::timeout(idle, writer.close(None)).await;
+                break;
+            }
+        }
+
+        // Both halves have been dropped
+        if (sink_closed || sink_dropped) && incoming.is_closed() {
+            // Write the close frame, if any, otherwise start the closing handshake
+            if !sink_closed {
+                writer.control = Some(Message::Close(None));
+            }
+            let _ = runtime::timeout(metadata.close_timeout, writer.finish()).await;
+            break;
+        }
+    }
+
+    // No-op if already disconnected by an error
+    metadata.state.disconnected(DisconnectReason::Closed);
+
+    // Drop the connection before waiting for the stream, that may never be polled again
+    drop((writer, ws_rx));
+
+    if let Some(e) = failure {
+        let _ = incoming.send(Err(e)).await;
+    }
+}
+
+/// Outcome of [`Writer::write_next`]
+enum Written {
+    /// Message of the send buffer written
+    Queued,
+    /// Control frame written
+    Control,
+    /// The send buffer has been closed (or the sink dropped)
+    End,
+}
+
+/// Write half of the connection, driven by the task loop
+///
+/// The writes progress (waiting for space in the write buffer and flushing) in a branch of the loop,
+/// so the other branches (i.e. reading) aren't blocked by a slow write.
+struct Writer<S> {
+    ws: S,
+    /// Frame to write before the queued messages (keepalive ping or close frame)
+    control: Option<Message>,
+    /// Message waiting for space in the write buffer, and if it comes from the send buffer
+    pending: Option<(Message, bool)>,
+    /// Written messages not flushed yet
+    unflushed: bool,
+}
+
+impl<S> Writer<S>
+where
+    S: SinkTrait<Message, Error = WsError> + Unpin,
+{
+    fn new(ws: S) -> Self {
+        Self {
+            ws,
+            control: None,
+            pending: None,
+            unflushed: false,
+        }
+    }
+
+    /// Check if something must be written (or flushed)
+    #[inline]
+    fn is_busy(&self) -> bool {
+        self.control.is_some() || self.pending.is_some() || self.unflushed
+    }
+
+    /// Write the control frame, if any, otherwise the next message of the send buffer (if `outgoing` is set)
+    ///
+    /// Cancel safe: the message taken is kept until written, and the flush is resumed by the next call.
+    async fn write_next(
+        &mut self,
+        outgoing: Option<&mut queue::Receiver>,
+    ) -> Result<Written, Error> {
+        if self.pending.is_none() {
+            self.pending = self.control.take().map(|msg| (msg, false));
+        }
+
+        if self.pending.is_none() {
+            // Flush once for all the queued messages (i.e. sent with `Sink::send_batch`)
+            if self.unflushed && outgoing.as_ref().is_none_or(|outgoing| outgoing.is_empty()) {
+                self.ws.flush().await?;
+                self.unflushed = false;
+            }
+
+            match outgoing {
+                Some(outgoing) => match outgoing.next().await {
+                    Some(msg) => self.pending = Some((msg, true)),
+                    None => return Ok(Written::End),
+                },
+                // Nothing to write
+                None => future::pending().await,
+            }
+        }
+
+        future::poll_fn(|cx| self.ws.poll_ready_unpin(cx)).await?;
+
+        let (msg, queued) = self.pending.take().expect("message taken above");
+        self.ws.start_send_unpin(msg)?;
+        self.unflushed = true;
+
+        Ok(if queued {
+            Written::Queued
+        } else {
+            Written::Control
+        })
+    }
+
+    /// Write the pending frames and flush
+    async fn finish(&mut self) -> Result<(), Error> {
+        while self.pending.is_some() || self.control.is_some() {
+            self.write_next(None).await?;
+        }
+
+        Ok(self.ws.flush().await?)
+    }
+
+    /// Write a close frame, after the pending messages
+    async fn close(&mut self, frame: Option<CloseFrame<'static>>) -> Result<(), Error> {
+        Ok(self.ws.send(Message::Close(frame)).await?)
+    }
+}
+
+/// Keepalive pings waiting for the pong
+#[derive(Debug, Default)]
+struct Pings {
+    /// Sequence number and send time, oldest first
+    unanswered: VecDeque<(u64, Instant)>,
+    /// Sequence number of the next ping
+    seq: u64,
+}
+
+impl Pings {
+    /// Register a new ping, returning its payload
+    fn next(&mut self) -> Vec<u8> {
+        let seq: u64 = self.seq;
+        self.seq += 1;
+        self.unanswered.push_back((seq, Instant::now()));
+
+        let mut payload: Vec<u8> = PING_PREFIX.to_vec();
+        payload.extend_from_slice(&seq.to_be_bytes());
+        payload
+    }
+
+    /// A pong has been received: the pings up to the echoed one are answered
+    ///
+    /// The other pongs (i.e. unsolicited or replying to the pings of the sink) prove the connection is alive too,
+    /// so all the pings are considered answered.
+    fn answered(&mut self, payload: &[u8]) {
+        let seq: Option<u64> = payload
+            .strip_prefix(PING_PREFIX)
+            .and_then(|seq| seq.try_into().ok())
+            .map(u64::from_be_bytes);
+
+        match seq {
+            Some(seq) => self.unanswered.retain(|(s, ..)| *s > seq),
+            None => self.unanswered.clear(),
+        }
+    }
+
+    /// When the oldest unanswered ping was sent
+    #[inline]
+    fn oldest(&self) -> Option<Instant> {
+        self.unanswered.front().map(|(.., sent_at)| *sent_at)
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.unanswered.len()
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.unanswered.is_empty()
+    }
+
+    #[inline]
+    fn clear(&mut self) {
+        self.unanswered.clear();
+    }
+}
+
+/// Keepalive ticker, with the first tick after `interval`
+#[inline]
+fn new_ticker(interval: Duration) -> Interval {
+    Interval::new(interval)
+}
+
+/// Wait for the next keepalive tick (forever, if keepalive is disabled)
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Mark the connection as failed, returning the error to forward to the stream
+fn fail(metadata: &Metadata, e: Error) -> Option<Error> {
+    metadata
+        .state
+        .disconnected(DisconnectReason::Error(e.to_string()));
+    Some(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+    use tokio::time;
+    use url::Url;
+
+    use super::*;
+    use crate::test_util::raw;
+    use crate::{ConnectionMode, ConnectionState};
+
+    /// Server sending `messages` text messages, then reading without answering the pings
+    ///
+    /// The receiver completes when the client drops the connection.
+    async fn mute_server(messages: usize) -> (Url, oneshot::Receiver<Vec<u8>>) {
+        raw::server(raw::frame(0x1, b"hello").repeat(messages)).await
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_timeout() {
+        let (url, closed) = mute_server(1).await;
+        let opts = ConnectionOptions::new()
+            .keepalive(Duration::from_millis(100), Duration::from_millis(200));
+        let (tx, mut rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            rx.next().await.unwrap().unwrap(),
+            Message::Text(String::from("hello"))
+        );
+
+        let res = time::timeout(Duration::from_secs(5), rx.next())
+            .await
+            .expect("keepalive timeout not triggered");
+        assert!(matches!(res, Some(Err(Error::KeepaliveTimeout))));
+        assert!(rx.next().await.is_none());
+
+        assert!(rx.last_pong().is_none());
+        assert!(matches!(tx.state(), ConnectionState::Disconnected(..)));
+        raw::wait_closed(closed).await;
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_timeout_stream_not_polled() {
+        let (url, closed) = mute_server(1).await;
+        let opts = ConnectionOptions::new()
+            .keepalive(Duration::from_millis(100), Duration::from_millis(200))
+            .receive_buffer(2);
+        let (_tx, mut rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+
+        // The error fills the receive buffer: the connection is closed before waiting for the stream
+        raw::wait_closed(closed).await;
+
+        assert!(matches!(rx.next().await, Some(Ok(Message::Text(..)))));
+        assert!(matches!(
+            rx.next().await,
+            Some(Err(Error::KeepaliveTimeout))
+        ));
+        assert!(rx.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_stream_not_polled() {
+        let (url, closed) = mute_server(1).await;
+        let opts = ConnectionOptions::new()
+            .idle_timeout(Duration::from_millis(200), false)
+            .receive_buffer(2);
+        let (_tx, mut rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+
+        raw::wait_closed(closed).await;
+
+        assert!(matches!(rx.next().await, Some(Ok(Message::Text(..)))));
+        assert!(matches!(rx.next().await, Some(Err(Error::IdleTimeout))));
+        assert!(rx.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_close_then_drop() {
+        let (url, closed) = raw::server(Vec::new()).await;
+        let opts = ConnectionOptions::new().send_buffer(8);
+        let (mut tx, rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+
+        tx.send(Message::Text(String::from("hello"))).await.unwrap();
+        tx.close().await.unwrap();
+        drop((tx, rx));
+
+        // The queued message and the close frame are written before dropping the connection
+        let received: Vec<u8> = raw::wait_closed(closed).await;
+        assert_eq!(received[0], 0x81);
+        assert!(received.contains(&0x88));
+    }
+
+    #[tokio::test]
+    async fn test_drop_after_close_frame() {
+        let (url, closed) = raw::server(Vec::new()).await;
+        let opts = ConnectionOptions::new().send_buffer(8);
+        let (mut tx, rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+
+        // The close frame is written, but the server never answers it
+        tx.close().await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        drop((tx, rx));
+
+        let received: Vec<u8> = raw::wait_closed(closed).await;
+        assert_eq!(received[0], 0x88);
+    }
+}
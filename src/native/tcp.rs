@@ -0,0 +1,291 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! TCP connection (DNS resolution and happy eyeballs)
+
+use std::fmt;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+use super::error::Error;
+use super::runtime::{self, TcpStream};
+use super::target::Target;
+use super::timings::ConnectTimings;
+use crate::ConnectionOptions;
+
+/// Delay before starting the next connection attempt (RFC 8305, section 5)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Future returned by [`DnsResolver::resolve`]
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Custom DNS resolver, used by the direct connections
+///
+/// Useful for testing or split-horizon DNS. The addresses are tried in the returned order,
+/// alternating the IPv6 and IPv4 ones (see [`ConnectionOptions::dns_resolver`](crate::ConnectionOptions::dns_resolver)).
+pub trait DnsResolver: fmt::Debug + Send + Sync {
+    /// Resolve `host` to the socket addresses to connect to
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a>;
+}
+
+/// Resolve the host of the target and connect to it
+pub(super) async fn connect(
+    target: &Target<'_>,
+    opts: &ConnectionOptions,
+    timings: &mut ConnectTimings,
+) -> Result<TcpStream, Error> {
+    let addrs: Vec<SocketAddr> = match target.socket_addr() {
+        Some(addr) => vec![addr],
+        None => {
+            let start: Instant = Instant::now();
+            let addrs: Vec<SocketAddr> =
+                resolve(target.host_str, target.port, opts.dns_resolver.as_deref())
+                    .await
+                    .map_err(WsError::Io)?;
+            timings.dns = Some(start.elapsed());
+            addrs
+        }
+    };
+
+    let start: Instant = Instant::now();
+    let stream: TcpStream = happy_eyeballs(addrs, opts).await.map_err(WsError::Io)?;
+    timings.tcp_connect = Some(start.elapsed());
+
+    Ok(stream)
+}
+
+/// Connect to the proxy
+pub(super) async fn connect_proxy(
+    proxy: SocketAddr,
+    opts: &ConnectionOptions,
+    timings: &mut ConnectTimings,
+) -> Result<TcpStream, Error> {
+    if proxy.ip().is_unspecified() || proxy.port() == 0 {
+        return Err(Error::InvalidProxyAddress(proxy));
+    }
+
+    let start: Instant = Instant::now();
+    let stream: TcpStream = attempt(proxy, opts).await.map_err(|e| {
+        if e.kind() == ErrorKind::ConnectionRefused {
+            Error::ProxyConnectionRefused
+        } else {
+            Error::Ws(Box::new(WsError::Io(e)))
+        }
+    })?;
+    timings.tcp_connect = Some(start.elapsed());
+
+    Ok(stream)
+}
+
+async fn resolve(
+    host: &str,
+    port: u16,
+    resolver: Option<&dyn DnsResolver>,
+) -> io::Result<Vec<SocketAddr>> {
+    match resolver {
+        Some(resolver) => resolver.resolve(host, port).await,
+        None => runtime::lookup_host(host, port).await,
+    }
+}
+
+/// Race the connection attempts, with a short stagger, and return the first established (RFC 8305)
+async fn happy_eyeballs(
+    mut addrs: Vec<SocketAddr>,
+    opts: &ConnectionOptions,
+) -> io::Result<TcpStream> {
+    // A socket bound to a local address can only connect to the addresses of the same family
+    if let Some(bind_addr) = opts.bind_addr {
+        addrs.retain(|addr| addr.is_ipv6() == bind_addr.is_ipv6());
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                "no resolved address of the same family of the bind address",
+            ));
+        }
+    }
+
+    let mut addrs = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error: Option<io::Error> = None;
+
+    match addrs.next() {
+        Some(addr) => attempts.push(attempt(addr, opts)),
+        None => return Err(io::Error::new(ErrorKind::NotFound, "no address resolved")),
+    }
+
+    while !attempts.is_empty() {
+        tokio::select! {
+            Some(res) = attempts.next() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    // Don't wait the delay: start the next attempt immediately
+                    if let Some(addr) = addrs.next() {
+                        attempts.push(attempt(addr, opts));
+                    }
+
+                    last_error = Some(e);
+                }
+            },
+            _ = runtime::sleep(CONNECTION_ATTEMPT_DELAY), if addrs.len() > 0 => {
+                if let Some(addr) = addrs.next() {
+                    attempts.push(attempt(addr, opts));
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| io::Error::from(ErrorKind::ConnectionRefused)))
+}
+
+async fn attempt(addr: SocketAddr, opts: &ConnectionOptions) -> io::Result<TcpStream> {
+    let res: io::Result<TcpStream> = match socket(addr, opts) {
+        Ok(socket) => runtime::connect(socket, addr).await,
+        Err(e) => Err(e),
+    };
+
+    res.inspect_err(|e| {
+        tracing::debug!(error = %e, "Connection attempt to {addr} failed.");
+    })
+}
+
+/// Create the non-blocking socket to connect to `addr`, bound to the local address and device (if any)
+fn socket(addr: SocketAddr, opts: &ConnectionOptions) -> io::Result<Socket> {
+    let socket: Socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_nonblocking(true)?;
+    configure(&socket, opts)?;
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(device) = &opts.bind_device {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+
+    if let Some(bind_addr) = opts.bind_addr {
+        socket.bind(&bind_addr.into())?;
+    }
+
+    Ok(socket)
+}
+
+/// Apply the socket options (before connecting)
+fn configure(socket: &Socket, opts: &ConnectionOptions) -> io::Result<()> {
+    socket.set_nodelay(opts.nodelay)?;
+
+    if let Some((idle, _interval, _retries)) = opts.tcp_keepalive {
+        let keepalive: TcpKeepalive = TcpKeepalive::new().with_time(idle);
+
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "fuchsia",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "windows",
+        ))]
+        let keepalive: TcpKeepalive = keepalive.with_interval(_interval);
+
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "fuchsia",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+        ))]
+        let keepalive: TcpKeepalive = keepalive.with_retries(_retries);
+
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+
+    Ok(())
+}
+
+/// Alternate the address families, starting with the one of the first address (RFC 8305, section 4)
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6: bool = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let len: usize = addrs.len();
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+    let mut addrs: Vec<SocketAddr> = Vec::with_capacity(len);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::Arc;
+
+    use url::Url;
+
+    use super::*;
+    use crate::test_util::EchoServer;
+    use crate::ConnectionMode;
+
+    /// Resolve to an unroutable IPv6 address (discard prefix, RFC 6666), then to the loopback
+    #[derive(Debug)]
+    struct Unroutable;
+
+    impl DnsResolver for Unroutable {
+        fn resolve<'a>(&'a self, _host: &'a str, port: u16) -> ResolveFuture<'a> {
+            Box::pin(async move {
+                Ok(vec![
+                    SocketAddr::new(Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 1).into(), port),
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+                ])
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() {
+        let (addr, _server) = EchoServer::new().spawn().await;
+        let url: Url = Url::parse(&format!("ws://echo.test:{}", addr.port())).unwrap();
+        let opts = ConnectionOptions::new()
+            .dns_resolver(Arc::new(Unroutable))
+            .timeout(Duration::from_secs(10));
+
+        // The IPv4 attempt starts after the stagger, without waiting for the IPv6 one
+        let start: Instant = Instant::now();
+        let (tx, _rx) = crate::connect(&url, &ConnectionMode::Direct, opts)
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(tx.peer_addr(), Some(addr));
+    }
+
+    #[test]
+    fn test_interleave() {
+        let v4 = |port: u16| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        let v6 = |port: u16| SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port);
+
+        assert_eq!(
+            interleave(vec![v6(1), v6(2), v6(3), v4(4)]),
+            vec![v6(1), v4(4), v6(2), v6(3)]
+        );
+        assert_eq!(
+            interleave(vec![v4(1), v4(2), v6(3), v6(4)]),
+            vec![v4(1), v6(3), v4(2), v6(4)]
+        );
+        assert!(interleave(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection timings
+
+use std::time::Duration;
+
+/// Time taken by each phase of the connection
+///
+/// The phases not applying to the connection are `None` (i.e. `tls` for a `ws` URL, `dns` for an IP address
+/// or in proxy mode). With the redirects enabled, the phases are the ones of the last connection,
+/// while `total` includes the redirects.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectTimings {
+    /// DNS resolution
+    pub dns: Option<Duration>,
+    /// TCP connection, to the server or the proxy (`None` in tor mode)
+    pub tcp_connect: Option<Duration>,
+    /// SOCKS5 or HTTP `CONNECT` proxy negotiation
+    pub proxy: Option<Duration>,
+    /// Wait for the embedded tor client to be bootstrapped (almost zero if already bootstrapped)
+    pub tor_bootstrap: Option<Duration>,
+    /// Tor circuit building and stream opening
+    pub tor_circuit: Option<Duration>,
+    /// TLS handshake
+    pub tls: Option<Duration>,
+    /// WebSocket handshake (HTTP upgrade)
+    pub handshake: Duration,
+    /// Whole connection process
+    pub total: Duration,
+}
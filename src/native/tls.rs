@@ -0,0 +1,360 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! TLS
+
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(all(feature = "dangerous-tls", not(feature = "native-tls")))]
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+#[cfg(all(feature = "dangerous-tls", not(feature = "native-tls")))]
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::pki_types::ServerName as RustlsServerName;
+#[cfg(all(feature = "dangerous-tls", not(feature = "native-tls")))]
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::ClientConfig;
+#[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+use tokio_rustls::rustls::RootCertStore;
+#[cfg(all(feature = "dangerous-tls", not(feature = "native-tls")))]
+use tokio_rustls::rustls::{DigitallySignedStruct, SignatureScheme};
+#[cfg(feature = "rustls")]
+use tokio_rustls::TlsConnector as RustlsConnector;
+use tokio_tungstenite::tungstenite::client::uri_mode;
+#[cfg(feature = "rustls")]
+use tokio_tungstenite::tungstenite::error::TlsError;
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::stream::Mode;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{Connector, MaybeTlsStream};
+
+use super::error::Error;
+use crate::ConnectionOptions;
+
+/// Build the TLS connector
+///
+/// `None` means the default one of the enabled TLS backend.
+/// If both the backends are enabled, `native-tls` is used: only a custom `rustls` config selects `rustls`.
+#[cfg_attr(not(feature = "rustls"), allow(unused_variables))]
+pub(super) fn connector(opts: &ConnectionOptions) -> Result<Option<Connector>, Error> {
+    #[cfg(feature = "rustls")]
+    if let Some(config) = &opts.tls_config {
+        return Ok(Some(Connector::Rustls(config.clone())));
+    }
+
+    #[cfg(feature = "native-tls")]
+    return native_tls_connector(opts);
+
+    #[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+    return rustls_connector(opts);
+
+    #[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+    Ok(None)
+}
+
+/// Build the `native-tls` connector, with the root certificates of the options (if any)
+#[cfg(feature = "native-tls")]
+#[cfg_attr(not(feature = "rustls"), allow(unused_variables, unused_mut))]
+fn native_tls_connector(opts: &ConnectionOptions) -> Result<Option<Connector>, Error> {
+    let mut builder = native_tls::TlsConnector::builder();
+    let mut custom: bool = false;
+
+    // The options are available with the `rustls` feature only
+    #[cfg(feature = "rustls")]
+    for cert in opts.root_certificates.iter() {
+        let cert = native_tls::Certificate::from_der(cert).map_err(|e| WsError::Tls(e.into()))?;
+        builder.add_root_certificate(cert);
+        custom = true;
+    }
+
+    #[cfg(feature = "dangerous-tls")]
+    if opts.danger_accept_invalid_certs {
+        tracing::warn!("TLS certificate verification is DISABLED: the connection is vulnerable to MITM attacks!");
+
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+        custom = true;
+    }
+
+    if !custom {
+        return Ok(None);
+    }
+
+    let connector = builder.build().map_err(|e| WsError::Tls(e.into()))?;
+    Ok(Some(Connector::NativeTls(connector)))
+}
+
+/// Build the `rustls` connector
+#[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+fn rustls_connector(opts: &ConnectionOptions) -> Result<Option<Connector>, Error> {
+    #[cfg(feature = "dangerous-tls")]
+    if opts.danger_accept_invalid_certs {
+        tracing::warn!("TLS certificate verification is DISABLED: the connection is vulnerable to MITM attacks!");
+
+        let provider = Arc::new(crypto::ring::default_provider());
+        let config: ClientConfig = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| WsError::Tls(e.into()))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+            .with_no_client_auth();
+        return Ok(Some(with_alpn(config, opts)));
+    }
+
+    // The default config (see `wrap_stream`) has no ALPN
+    if opts.root_certificates.is_empty() && opts.alpn_protocols.is_empty() {
+        return Ok(None);
+    }
+
+    // Keep the default roots, to add the custom ones
+    let mut roots: RootCertStore = default_roots();
+
+    for cert in opts.root_certificates.iter().cloned() {
+        roots.add(cert).map_err(|e| WsError::Tls(e.into()))?;
+    }
+
+    let config: ClientConfig = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Some(with_alpn(config, opts)))
+}
+
+/// Set the ALPN protocols of the options
+#[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+fn with_alpn(mut config: ClientConfig, opts: &ConnectionOptions) -> Connector {
+    config.alpn_protocols = opts.alpn_protocols.clone();
+    Connector::Rustls(Arc::new(config))
+}
+
+/// Upgrade the stream to TLS, if the scheme of the request requires it
+///
+/// Same as the first step of `tokio_tungstenite::client_async_tls_with_config`, split from the WebSocket
+/// handshake to time it. The host of the request is the server name.
+#[cfg_attr(
+    not(any(feature = "rustls", feature = "native-tls")),
+    allow(unused_variables)
+)]
+pub(super) async fn wrap_stream<S>(
+    request: &Request,
+    stream: S,
+    connector: Option<Connector>,
+) -> Result<MaybeTlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    if let Mode::Plain = uri_mode(request.uri())? {
+        return Ok(MaybeTlsStream::Plain(stream));
+    }
+
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    {
+        let domain: &str = request.uri().host().ok_or_else(Error::empty_host)?;
+
+        match connector {
+            #[cfg(feature = "native-tls")]
+            Some(Connector::NativeTls(connector)) => native_tls(stream, domain, connector).await,
+            #[cfg(feature = "rustls")]
+            Some(Connector::Rustls(config)) => rustls(stream, domain, config).await,
+            #[cfg(feature = "native-tls")]
+            None => {
+                let connector =
+                    native_tls::TlsConnector::new().map_err(|e| WsError::Tls(e.into()))?;
+                native_tls(stream, domain, connector).await
+            }
+            #[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+            None => {
+                let config: ClientConfig = ClientConfig::builder()
+                    .with_root_certificates(default_roots())
+                    .with_no_client_auth();
+                rustls(stream, domain, Arc::new(config)).await
+            }
+            _ => Ok(MaybeTlsStream::Plain(stream)),
+        }
+    }
+
+    // Already checked by `Target::parse`
+    #[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+    Err(Error::Ws(Box::new(WsError::Url(
+        UrlError::TlsFeatureNotEnabled,
+    ))))
+}
+
+#[cfg(feature = "rustls")]
+async fn rustls<S>(
+    stream: S,
+    domain: &str,
+    config: Arc<ClientConfig>,
+) -> Result<MaybeTlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // Without the brackets of the IPv6 addresses
+    let domain: &str = domain
+        .strip_prefix('[')
+        .and_then(|domain| domain.strip_suffix(']'))
+        .unwrap_or(domain);
+    let server_name: RustlsServerName<'static> = RustlsServerName::try_from(domain)
+        .map_err(|_| WsError::Tls(TlsError::InvalidDnsName))?
+        .to_owned();
+
+    // The IO errors are mapped to the certificate errors, if any
+    let stream = RustlsConnector::from(config)
+        .connect(server_name, stream)
+        .await
+        .map_err(WsError::Io)?;
+    Ok(MaybeTlsStream::Rustls(stream))
+}
+
+#[cfg(feature = "native-tls")]
+async fn native_tls<S>(
+    stream: S,
+    domain: &str,
+    connector: native_tls::TlsConnector,
+) -> Result<MaybeTlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(domain, stream)
+        .await
+        .map_err(|e| WsError::Tls(e.into()))?;
+    Ok(MaybeTlsStream::NativeTls(stream))
+}
+
+/// The `webpki` roots
+#[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+fn default_roots() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+/// Accept any server certificate, still verifying the handshake signatures
+#[cfg(all(feature = "dangerous-tls", not(feature = "native-tls")))]
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+#[cfg(all(feature = "dangerous-tls", not(feature = "native-tls")))]
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(all(test, feature = "rustls"))]
+mod tests {
+    use super::*;
+
+    /// Self-signed certificate (`CN=localhost`)
+    const CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBfjCCASWgAwIBAgIUTl5KGZ8qJ8ABnBCGS0GGPzWZ9o0wCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJbG9jYWxob3N0MCAXDTI2MTAxNDA3MTUyNVoYDzIxMjYwOTIw
+MDcxNTI1WjAUMRIwEAYDVQQDDAlsb2NhbGhvc3QwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAATN73warmRiKFSEhpoCVAA2Jefr5F6Fs8V82LD8fmSLTzP8zvpu1fIf
+ALMmftVLYQziX4t00Y61d/AK0TcKYQ+ko1MwUTAdBgNVHQ4EFgQUFa0aT1c0TCVh
+X8P52aD2yOpBRQ8wHwYDVR0jBBgwFoAUFa0aT1c0TCVhX8P52aD2yOpBRQ8wDwYD
+VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiBYGQz1egdQWo6pl1rFeiQ4
+8i7GIQyfQHkT4SRjRK6a+wIgCNPRMzMaAQytp7kjg0Iw4brv93kgffdqppk3YX8Y
+xgA=
+-----END CERTIFICATE-----
+";
+
+    fn with_root_certificate() -> ConnectionOptions {
+        ConnectionOptions::new()
+            .add_root_certificates_pem(CERT.as_bytes())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_custom_config() {
+        let config: ClientConfig = ClientConfig::builder()
+            .with_root_certificates(tokio_rustls::rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let opts = with_root_certificate().tls_config(Arc::new(config));
+        assert!(matches!(
+            connector(&opts).unwrap(),
+            Some(Connector::Rustls(..))
+        ));
+    }
+
+    #[test]
+    #[cfg(not(feature = "native-tls"))]
+    fn test_rustls_backend() {
+        let opts = with_root_certificate();
+        assert!(matches!(
+            connector(&opts).unwrap(),
+            Some(Connector::Rustls(..))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "native-tls")]
+    fn test_native_tls_precedence() {
+        // The default connector
+        let opts = ConnectionOptions::new();
+        assert!(connector(&opts).unwrap().is_none());
+
+        // The root certificates don't switch to `rustls`
+        let opts = with_root_certificate();
+        assert!(matches!(
+            connector(&opts).unwrap(),
+            Some(Connector::NativeTls(..))
+        ));
+
+        #[cfg(feature = "dangerous-tls")]
+        {
+            let opts = ConnectionOptions::new().danger_accept_invalid_certs(true);
+            assert!(matches!(
+                connector(&opts).unwrap(),
+                Some(Connector::NativeTls(..))
+            ));
+        }
+    }
+}
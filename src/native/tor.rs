@@ -2,40 +2,108 @@
 // Distributed under the MIT software license
 
 //! Tor
+//!
+//! The embedded client (arti) doesn't have its own logging config: it emits `tracing` events, under the
+//! `arti_client` and `tor_*` targets, to the subscriber of the application. To silence the bootstrap output,
+//! filter them there (i.e. `EnvFilter::new("info,arti_client=warn,tor_=warn")` with `tracing-subscriber`).
 
+#[cfg(feature = "tor-launch-service")]
+use std::collections::hash_map::RandomState;
 use std::fmt;
+use std::future::Future;
+#[cfg(feature = "tor-launch-service")]
+use std::hash::BuildHasher;
+use std::hash::{Hash, Hasher};
 #[cfg(feature = "tor-launch-service")]
 use std::net::SocketAddr;
+#[cfg(feature = "tor-launch-service")]
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
+use std::str::FromStr;
 #[cfg(feature = "tor-launch-service")]
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "tor-launch-service")]
 use arti_client::config::onion_service::OnionServiceConfigBuilder;
-use arti_client::config::{CfgPath, ConfigBuildError, TorClientConfigBuilder};
-use arti_client::{DataStream, TorClient, TorClientConfig};
+use arti_client::config::pt::TransportConfigBuilder;
+pub use arti_client::config::TorClientConfigBuilder;
+use arti_client::config::{
+    BridgeConfigBuilder, BridgeParseError, CfgPath, ConfigBuildError, PtTransportName,
+};
+pub use arti_client::status::BootstrapStatus;
+pub use arti_client::{DataStream, IsolationToken};
+use arti_client::{ErrorKind, HasKind, StreamPrefs, TorClient, TorClientConfig};
+#[cfg(feature = "tor-launch-service")]
+use futures_util::future::{self, AbortHandle, Aborted};
 #[cfg(feature = "tor-launch-service")]
 use futures_util::task::{SpawnError, SpawnExt};
-use tokio::sync::OnceCell;
+#[cfg(feature = "tor-launch-service")]
+use futures_util::Stream;
+use futures_util::StreamExt;
+#[cfg(feature = "tor-launch-service")]
+use tokio::sync::Notify;
+use tokio::sync::RwLock;
+#[cfg(feature = "tor-launch-service")]
+use tor_cell::relaycell::msg::Connected;
+use tor_config::PaddingLevel;
+use tor_hscrypto::pk::HsId;
 #[cfg(feature = "tor-launch-service")]
 use tor_hsrproxy::config::{
-    Encapsulation, ProxyAction, ProxyConfigBuilder, ProxyConfigError, ProxyPattern, ProxyRule,
-    TargetAddr,
+    Encapsulation, ProxyAction, ProxyConfig, ProxyConfigBuilder, ProxyConfigError, ProxyPattern,
+    ProxyRule, TargetAddr,
 };
 #[cfg(feature = "tor-launch-service")]
 use tor_hsrproxy::OnionServiceReverseProxy;
 #[cfg(feature = "tor-launch-service")]
-use tor_hsservice::{HsNickname, InvalidNickname, OnionServiceConfig, RunningOnionService};
+pub use tor_hsservice::status::{OnionServiceStatus, State as OnionServiceState};
+#[cfg(feature = "tor-launch-service")]
+use tor_hsservice::{
+    HsNickname, InvalidNickname, OnionServiceConfig, RendRequest, RunningOnionService,
+    StreamRequest,
+};
+#[cfg(feature = "tor-launch-service")]
+use tor_proto::stream::IncomingStreamRequest;
 use tor_rtcompat::PreferredRuntime;
 
-static TOR_CLIENT: OnceCell<TorClient<PreferredRuntime>> = OnceCell::const_new();
+use super::runtime;
+use super::timings::ConnectTimings;
+
+/// Shared tor client, with the config it was bootstrapped with
+static TOR_CLIENT: RwLock<Option<SharedClient>> = RwLock::const_new(None);
+
+/// Length of the service id of the v3 onion addresses
+const ONION_V3_ID_LEN: usize = 56;
 
 #[derive(Debug)]
 pub enum Error {
-    /// Arti Client error
+    /// Arti Client error (outside of the bootstrap, connect and onion service launch phases)
     ArtiClient(arti_client::Error),
+    /// Bootstrap of the tor client failed
+    Bootstrap(arti_client::Error),
+    /// The state or cache directory is not usable (i.e. not writable, or writable by other users)
+    Storage {
+        /// Custom path of the directories (`None` for the default ones)
+        path: Option<PathBuf>,
+        /// Arti Client error
+        source: arti_client::Error,
+    },
+    /// Connection to the target failed
+    Connect {
+        /// Target domain (or IP address)
+        domain: String,
+        /// Target port
+        port: u16,
+        /// Arti Client error
+        source: arti_client::Error,
+    },
+    /// Launch of the onion service failed
+    #[cfg(feature = "tor-launch-service")]
+    LaunchOnionService(arti_client::Error),
     /// Config builder error
     ConfigBuilder(ConfigBuildError),
+    /// Invalid bridge line
+    InvalidBridgeLine(BridgeParseError),
     /// Proxy config error
     #[cfg(feature = "tor-launch-service")]
     ProxyConfig(ProxyConfigError),
@@ -45,21 +113,127 @@ pub enum Error {
     /// Spawn error
     #[cfg(feature = "tor-launch-service")]
     Spawn(SpawnError),
+    /// Bootstrap timeout
+    BootstrapTimeout,
+    /// Malformed onion address (not a v3 one)
+    InvalidOnionAddress {
+        /// Domain
+        domain: String,
+        /// What's wrong
+        reason: String,
+    },
+    /// Onion service name not found
+    #[cfg(feature = "tor-launch-service")]
+    OnionNameNotFound,
+    /// Onion service broken
+    #[cfg(feature = "tor-launch-service")]
+    OnionServiceBroken(Option<String>),
+    /// Onion service not running within the timeout
+    #[cfg(feature = "tor-launch-service")]
+    OnionServiceTimeout,
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ArtiClient(e) => Some(e),
+            Self::Bootstrap(e) => Some(e),
+            Self::Storage { source, .. } => Some(source),
+            Self::Connect { source, .. } => Some(source),
+            #[cfg(feature = "tor-launch-service")]
+            Self::LaunchOnionService(e) => Some(e),
+            Self::ConfigBuilder(e) => Some(e),
+            Self::InvalidBridgeLine(e) => Some(e),
+            #[cfg(feature = "tor-launch-service")]
+            Self::ProxyConfig(e) => Some(e),
+            #[cfg(feature = "tor-launch-service")]
+            Self::InvalidNickname(e) => Some(e),
+            #[cfg(feature = "tor-launch-service")]
+            Self::Spawn(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::ArtiClient(e) => write!(f, "{e}"),
+            Self::Bootstrap(e) => write!(f, "tor bootstrap failed: {e}"),
+            Self::Storage { path, source } => {
+                // The root cause has the offending path and permissions
+                let mut cause: &dyn std::error::Error = source;
+                while let Some(source) = cause.source() {
+                    cause = source;
+                }
+
+                match path {
+                    Some(path) => {
+                        write!(f, "tor storage in {} not usable: {cause}", path.display())
+                    }
+                    None => write!(f, "tor storage not usable: {cause}"),
+                }
+            }
+            Self::Connect {
+                domain,
+                port,
+                source,
+            } => write!(f, "tor connection to {domain}:{port} failed: {source}"),
+            #[cfg(feature = "tor-launch-service")]
+            Self::LaunchOnionService(e) => write!(f, "onion service launch failed: {e}"),
             Self::ConfigBuilder(e) => write!(f, "{e}"),
+            Self::InvalidBridgeLine(e) => write!(f, "invalid bridge line: {e}"),
             #[cfg(feature = "tor-launch-service")]
             Self::ProxyConfig(e) => write!(f, "{e}"),
             #[cfg(feature = "tor-launch-service")]
             Self::InvalidNickname(e) => write!(f, "{e}"),
             #[cfg(feature = "tor-launch-service")]
             Self::Spawn(e) => write!(f, "{e}"),
+            Self::BootstrapTimeout => write!(f, "bootstrap timeout"),
+            Self::InvalidOnionAddress { domain, reason } => {
+                write!(f, "invalid onion address {domain}: {reason}")
+            }
+            #[cfg(feature = "tor-launch-service")]
+            Self::OnionNameNotFound => write!(f, "onion name not found"),
+            #[cfg(feature = "tor-launch-service")]
+            Self::OnionServiceBroken(problem) => match problem {
+                Some(problem) => write!(f, "onion service broken: {problem}"),
+                None => write!(f, "onion service broken"),
+            },
+            #[cfg(feature = "tor-launch-service")]
+            Self::OnionServiceTimeout => write!(f, "onion service not running within the timeout"),
+        }
+    }
+}
+
+/// The `arti` errors aren't comparable: the variants holding one are never equal.
+/// The config and parse errors are compared by their message.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::ConfigBuilder(a), Self::ConfigBuilder(b)) => a.to_string() == b.to_string(),
+            (Self::InvalidBridgeLine(a), Self::InvalidBridgeLine(b)) => {
+                a.to_string() == b.to_string()
+            }
+            #[cfg(feature = "tor-launch-service")]
+            (Self::ProxyConfig(a), Self::ProxyConfig(b)) => a.to_string() == b.to_string(),
+            #[cfg(feature = "tor-launch-service")]
+            (Self::InvalidNickname(a), Self::InvalidNickname(b)) => a == b,
+            (Self::BootstrapTimeout, Self::BootstrapTimeout) => true,
+            (
+                Self::InvalidOnionAddress { domain, reason },
+                Self::InvalidOnionAddress {
+                    domain: other_domain,
+                    reason: other_reason,
+                },
+            ) => domain == other_domain && reason == other_reason,
+            #[cfg(feature = "tor-launch-service")]
+            (Self::OnionNameNotFound, Self::OnionNameNotFound) => true,
+            #[cfg(feature = "tor-launch-service")]
+            (Self::OnionServiceBroken(a), Self::OnionServiceBroken(b)) => a == b,
+            #[cfg(feature = "tor-launch-service")]
+            (Self::OnionServiceTimeout, Self::OnionServiceTimeout) => true,
+            _ => false,
         }
     }
 }
@@ -76,6 +250,12 @@ impl From<ConfigBuildError> for Error {
     }
 }
 
+impl From<BridgeParseError> for Error {
+    fn from(e: BridgeParseError) -> Self {
+        Self::InvalidBridgeLine(e)
+    }
+}
+
 #[cfg(feature = "tor-launch-service")]
 impl From<ProxyConfigError> for Error {
     fn from(e: ProxyConfigError) -> Self {
@@ -97,17 +277,195 @@ impl From<SpawnError> for Error {
     }
 }
 
-async fn init_tor_client(
-    custom_path: Option<&PathBuf>,
-) -> Result<TorClient<PreferredRuntime>, Error> {
+/// Embedded tor client config
+///
+/// The client is shared by all the connections: `custom_path`, `bridges`, `pt_binary` and `circuit_preemption` are applied only
+/// by the bootstrap. A later call with different values reuses the shared client, ignoring them with a warning:
+/// call [`shutdown`] before, to bootstrap a new client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TorConfig {
+    /// Path for cache and state data
+    ///
+    /// Mandatory for `android` and `ios` targets!
+    pub custom_path: Option<PathBuf>,
+    /// Bridge lines (i.e. `obfs4 <addr>:<port> <fingerprint> cert=<cert> iat-mode=0`)
+    ///
+    /// If empty, the client will connect directly to the tor network.
+    pub bridges: Vec<String>,
+    /// Path of the pluggable transport binary (i.e. `obfs4proxy` or `lyrebird`)
+    ///
+    /// Required when the bridges use a pluggable transport.
+    pub pt_binary: Option<PathBuf>,
+    /// Stream isolation token
+    ///
+    /// Connections with different tokens will never share a circuit.
+    /// By default (`None`), the connections may share the same circuits.
+    pub isolation: Option<IsolationToken>,
+    /// Bootstrap timeout
+    ///
+    /// By default (`None`), the bootstrap is unbounded.
+    pub bootstrap_timeout: Option<Duration>,
+    /// How many circuits are built in advance (see [`CircuitPreemption`])
+    pub circuit_preemption: CircuitPreemption,
+}
+
+/// Preemptive circuits building of the embedded tor client
+///
+/// The circuits built in advance reduce the latency of the first connections to a port,
+/// at the cost of bandwidth, CPU and battery.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CircuitPreemption {
+    /// Defaults of arti (up to 12 circuits, with 2 for each predicted port)
+    #[default]
+    Default,
+    /// More circuits in advance (up to 24, with 4 for each predicted port), for the latency-sensitive uses
+    Aggressive,
+    /// No circuit in advance and reduced padding, for the resource-constrained devices (i.e. mobile)
+    Lazy,
+}
+
+impl Hash for TorConfig {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.custom_path.hash(state);
+        self.bridges.hash(state);
+        self.pt_binary.hash(state);
+        // `IsolationToken` doesn't implement `Hash`
+        self.isolation.is_some().hash(state);
+        self.bootstrap_timeout.hash(state);
+        self.circuit_preemption.hash(state);
+    }
+}
+
+/// Launched onion service
+#[cfg(feature = "tor-launch-service")]
+#[derive(Clone)]
+pub struct OnionService {
+    service: Arc<RunningOnionService>,
+    hostname: String,
+    /// Set when the reverse proxy stop (with the error message, if any)
+    proxy: Arc<OnceLock<Option<String>>>,
+    /// Stop the reverse proxy task
+    abort: AbortHandle,
+    /// Notified when the reverse proxy task finishes
+    stopped: Arc<Notify>,
+}
+
+#[cfg(feature = "tor-launch-service")]
+impl OnionService {
+    /// Get running onion service
+    #[inline]
+    pub fn service(&self) -> &Arc<RunningOnionService> {
+        &self.service
+    }
+
+    /// Get onion service hostname (i.e. `<base32>.onion`)
+    ///
+    /// The hostname is derived from the service identity key,
+    /// so it's available also before the descriptors are published.
+    #[inline]
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Check if the reverse proxy is still forwarding the requests
+    #[inline]
+    pub fn is_proxy_running(&self) -> bool {
+        self.proxy.get().is_none()
+    }
+
+    /// Get the error that caused the reverse proxy to stop, if any
+    #[inline]
+    pub fn proxy_error(&self) -> Option<&str> {
+        self.proxy.get().and_then(|e| e.as_deref())
+    }
+
+    /// Get the current status of the service
+    #[inline]
+    pub fn status(&self) -> OnionServiceStatus {
+        self.service.status()
+    }
+
+    /// Get the status changes of the service (starting with the current status)
+    #[inline]
+    pub fn status_events(&self) -> impl Stream<Item = OnionServiceStatus> + Send {
+        self.service.status_events()
+    }
+
+    /// Wait until the service is running (introduction points established and descriptor published),
+    /// so it can be reached by the clients (i.e. with [`ConnectionMode::EmbeddedTor`](crate::ConnectionMode::EmbeddedTor)).
+    ///
+    /// See [`wait_until_running`] for the details.
+    #[inline]
+    pub async fn wait_until_running(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        wait_until_running(&self.service, timeout).await
+    }
+
+    /// Stop the reverse proxy, waiting for its task to finish, and the service
+    ///
+    /// The new requests are no longer forwarded, but the connections already forwarded aren't closed:
+    /// they end when either side closes them. The service is stopped when its last handle is dropped,
+    /// so drop the clones of this and of [`OnionService::service`] too.
+    pub async fn stop(self) {
+        // Registered before the check, to not miss the notification
+        let stopped = self.stopped.notified();
+
+        self.abort.abort();
+
+        if self.is_proxy_running() {
+            stopped.await;
+        }
+    }
+}
+
+/// Wait until the service is reachable: [`OnionServiceState::Running`] or [`OnionServiceState::Degraded`]
+/// (not all the introduction points established, but the descriptor published).
+///
+/// Returns [`Error::OnionServiceBroken`] if the service fails, and [`Error::OnionServiceTimeout`] on expiry.
+/// By default (`None`), the wait is unbounded.
+#[cfg(feature = "tor-launch-service")]
+pub async fn wait_until_running(
+    service: &RunningOnionService,
+    timeout: Option<Duration>,
+) -> Result<(), Error> {
+    let wait = async {
+        let mut events = service.status_events();
+
+        // The stream yields the current status first
+        while let Some(status) = events.next().await {
+            match status.state() {
+                OnionServiceState::Running | OnionServiceState::Degraded => return Ok(()),
+                OnionServiceState::Broken => {
+                    return Err(Error::OnionServiceBroken(
+                        status.current_problem().map(|p| format!("{p:?}")),
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        Err(Error::OnionServiceBroken(None))
+    };
+
+    match timeout {
+        Some(timeout) => runtime::timeout(timeout, wait)
+            .await
+            .map_err(|_| Error::OnionServiceTimeout)?,
+        None => wait.await,
+    }
+}
+
+fn build_tor_client_config<F>(config: &TorConfig, customize: F) -> Result<TorClientConfig, Error>
+where
+    F: FnOnce(&mut TorClientConfigBuilder),
+{
     // Construct default Tor Client config
-    let mut config = TorClientConfigBuilder::default();
+    let mut builder = TorClientConfigBuilder::default();
 
     // Enable hidden services
-    config.address_filter().allow_onion_addrs(true);
+    builder.address_filter().allow_onion_addrs(true);
 
     // Check if is set a custom arti cache path
-    if let Some(path) = custom_path {
+    if let Some(path) = &config.custom_path {
         let cache: PathBuf = path.join("cache");
         let state: PathBuf = path.join("state");
 
@@ -115,71 +473,770 @@ async fn init_tor_client(
         let state_dir: CfgPath = CfgPath::new(state.to_string_lossy().to_string());
 
         // Set custom paths
-        config.storage().cache_dir(cache_dir).state_dir(state_dir);
+        builder.storage().cache_dir(cache_dir).state_dir(state_dir);
+    }
+
+    // Configure bridges
+    let mut protocols: Vec<PtTransportName> = Vec::new();
+    for line in config.bridges.iter() {
+        let bridge: BridgeConfigBuilder = line.parse()?;
+
+        // Collect the pluggable transports used by the bridges.
+        // Direct bridges have an empty transport name.
+        if let Some(transport) = bridge.get_transport().filter(|t| !t.is_empty()) {
+            let protocol: PtTransportName =
+                transport.parse().map_err(|e| ConfigBuildError::Invalid {
+                    field: String::from("bridges"),
+                    problem: format!("{e}"),
+                })?;
+
+            if !protocols.contains(&protocol) {
+                protocols.push(protocol);
+            }
+        }
+
+        builder.bridges().bridges().push(bridge);
+    }
+
+    // Configure pluggable transport
+    if let Some(pt_binary) = &config.pt_binary {
+        if !protocols.is_empty() {
+            let mut transport = TransportConfigBuilder::default();
+            transport
+                .protocols(protocols)
+                .path(CfgPath::new(pt_binary.to_string_lossy().to_string()))
+                .run_on_startup(true);
+            builder.bridges().transports().push(transport);
+        }
+    }
+
+    // Configure preemptive circuits
+    match config.circuit_preemption {
+        CircuitPreemption::Default => {}
+        CircuitPreemption::Aggressive => {
+            builder
+                .preemptive_circuits()
+                .disable_at_threshold(24)
+                .min_exit_circs_for_port(4);
+        }
+        CircuitPreemption::Lazy => {
+            builder
+                .preemptive_circuits()
+                .disable_at_threshold(0)
+                .min_exit_circs_for_port(0);
+            builder.channel().padding(PaddingLevel::Reduced);
+        }
+    }
+
+    // Apply the caller customizations, overriding the above defaults
+    customize(&mut builder);
+
+    Ok(builder.build()?)
+}
+
+/// Map the bootstrap error, with the filesystem failures (i.e. an unwritable directory) to [`Error::Storage`]
+fn bootstrap_error(config: &TorConfig, e: arti_client::Error) -> Error {
+    match e.kind() {
+        ErrorKind::FsPermissions
+        | ErrorKind::PersistentStateAccessFailed
+        | ErrorKind::CacheAccessFailed => Error::Storage {
+            path: config.custom_path.clone(),
+            source: e,
+        },
+        _ => Error::Bootstrap(e),
+    }
+}
+
+/// Await the bootstrap future, within the timeout (if any)
+async fn bootstrap_within<F>(
+    timeout: Option<Duration>,
+    bootstrap: F,
+) -> Result<TorClient<PreferredRuntime>, Error>
+where
+    F: Future<Output = Result<TorClient<PreferredRuntime>, Error>>,
+{
+    match timeout {
+        Some(timeout) => runtime::timeout(timeout, bootstrap)
+            .await
+            .map_err(|_| Error::BootstrapTimeout)?,
+        None => bootstrap.await,
+    }
+}
+
+async fn init_tor_client<F>(
+    config: &TorConfig,
+    customize: F,
+) -> Result<TorClient<PreferredRuntime>, Error>
+where
+    F: FnOnce(&mut TorClientConfigBuilder),
+{
+    let client_config: TorClientConfig = build_tor_client_config(config, customize)?;
+    bootstrap_within(config.bootstrap_timeout, async {
+        TorClient::builder()
+            .config(client_config)
+            .create_bootstrapped()
+            .await
+            .map_err(|e| bootstrap_error(config, e))
+    })
+    .await
+}
+
+async fn init_tor_client_with_progress<F>(
+    config: &TorConfig,
+    progress: &F,
+) -> Result<TorClient<PreferredRuntime>, Error>
+where
+    F: Fn(BootstrapStatus),
+{
+    let client_config: TorClientConfig = build_tor_client_config(config, |_| {})?;
+    bootstrap_within(config.bootstrap_timeout, async {
+        let client: TorClient<PreferredRuntime> = TorClient::builder()
+            .config(client_config)
+            .create_unbootstrapped_async()
+            .await
+            .map_err(|e| bootstrap_error(config, e))?;
+
+        // Forward the bootstrap events until the bootstrap process terminates
+        let mut events = client
+            .bootstrap_events()
+            .take_until(Box::pin(client.bootstrap()));
+
+        while let Some(status) = events.next().await {
+            progress(status);
+        }
+
+        match events.take_future() {
+            // The events stream terminated before the bootstrap
+            Some(bootstrap) => bootstrap.await.map_err(|e| bootstrap_error(config, e))?,
+            None => {
+                if let Some(res) = events.take_result() {
+                    res.map_err(|e| bootstrap_error(config, e))?;
+                }
+            }
+        }
+
+        drop(events);
+
+        Ok(client)
+    })
+    .await
+}
+
+struct SharedClient {
+    client: TorClient<PreferredRuntime>,
+    /// Config the client was bootstrapped with
+    config: TorConfig,
+}
+
+impl SharedClient {
+    fn new(client: TorClient<PreferredRuntime>, config: &TorConfig) -> Self {
+        Self {
+            client,
+            config: config.clone(),
+        }
+    }
+
+    /// Get the client, warning if `config` requires a different one
+    fn get(&self, config: &TorConfig) -> TorClient<PreferredRuntime> {
+        if let Some(field) = config_mismatch(&self.config, config) {
+            tracing::warn!(
+                "The tor config has a different `{field}` than the one of the shared tor client, bootstrapped before: \
+                the config is IGNORED and the shared client is used. Call `tor::shutdown` to bootstrap a new client."
+            );
+        }
+
+        self.client.clone()
     }
+}
 
-    let config: TorClientConfig = config.build()?;
-    Ok(TorClient::builder()
-        .config(config)
-        .create_bootstrapped()
-        .await?)
+/// Get the first field of `config` requiring a different client than the `shared` one
+///
+/// The isolation token and the bootstrap timeout don't matter: they aren't part of the client config.
+fn config_mismatch(shared: &TorConfig, config: &TorConfig) -> Option<&'static str> {
+    if shared.custom_path != config.custom_path {
+        Some("custom_path")
+    } else if shared.bridges != config.bridges {
+        Some("bridges")
+    } else if shared.pt_binary != config.pt_binary {
+        Some("pt_binary")
+    } else if shared.circuit_preemption != config.circuit_preemption {
+        Some("circuit_preemption")
+    } else {
+        None
+    }
+}
+
+/// Get the shared tor client or init it using `init`
+async fn get_or_init_tor_client<F, Fut>(
+    config: &TorConfig,
+    init: F,
+) -> Result<TorClient<PreferredRuntime>, Error>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<TorClient<PreferredRuntime>, Error>>,
+{
+    if let Some(shared) = TOR_CLIENT.read().await.as_ref() {
+        return Ok(shared.get(config));
+    }
+
+    let mut lock = TOR_CLIENT.write().await;
+
+    // Check again: the client may have been initialized while waiting for the lock
+    if let Some(shared) = lock.as_ref() {
+        return Ok(shared.get(config));
+    }
+
+    let client: TorClient<PreferredRuntime> = init().await?;
+    *lock = Some(SharedClient::new(client.clone(), config));
+    Ok(client)
 }
 
 /// Get or init tor client
-async fn get_tor_client<'a>(
-    custom_path: Option<&PathBuf>,
-) -> Result<&'a TorClient<PreferredRuntime>, Error> {
-    TOR_CLIENT
-        .get_or_try_init(|| async { init_tor_client(custom_path).await })
-        .await
+#[inline]
+async fn get_tor_client(config: &TorConfig) -> Result<TorClient<PreferredRuntime>, Error> {
+    get_or_init_tor_client(config, || init_tor_client(config, |_| {})).await
+}
+
+/// Bootstrap the embedded tor client, reporting the progress to the `progress` callback.
+///
+/// The client is shared with [`connect`](crate::connect), so the following connections will not re-bootstrap it.
+/// If the client is already bootstrapped, `progress` is called once with the current status.
+pub async fn bootstrap_with_progress<F>(config: &TorConfig, progress: F) -> Result<(), Error>
+where
+    F: Fn(BootstrapStatus),
+{
+    if let Some(shared) = TOR_CLIENT.read().await.as_ref() {
+        progress(shared.get(config).bootstrap_status());
+        return Ok(());
+    }
+
+    get_or_init_tor_client(config, || init_tor_client_with_progress(config, &progress)).await?;
+
+    Ok(())
+}
+
+/// Bootstrap the embedded tor client, allowing to customize the arti config.
+///
+/// The `customize` callback receives the config builder with the defaults (onion addresses allowed,
+/// custom cache and state paths, bridges) already applied, so anything set there overrides them.
+///
+/// The client is shared with [`connect`](crate::connect), so the following connections will not re-bootstrap it.
+/// If the client is already bootstrapped, `customize` is not called.
+pub async fn bootstrap_with_custom_config<F>(config: &TorConfig, customize: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut TorClientConfigBuilder),
+{
+    get_or_init_tor_client(config, || init_tor_client(config, customize)).await?;
+    Ok(())
+}
+
+/// Shutdown the embedded tor client
+///
+/// The in-flight connections are not killed: they keep working until closed.
+/// The next connection will bootstrap a new client.
+pub async fn shutdown() {
+    let mut lock = TOR_CLIENT.write().await;
+    lock.take();
+}
+
+/// Open a raw stream to `domain:port` over the embedded tor client, without the WebSocket layer
+///
+/// For the advanced uses (i.e. another protocol over the same circuit): the connection options don't apply
+/// and there is no TLS, keepalive or close handshake, the bytes are the ones of the remote service.
+/// The stream can be upgraded to a WebSocket later with [`connect_on`](crate::connect_on).
+///
+/// The client is shared with [`connect`](crate::connect) and the onion addresses are validated as there.
+pub async fn connect_raw(domain: &str, port: u16, config: &TorConfig) -> Result<DataStream, Error> {
+    connect(domain, port, config, &mut ConnectTimings::default()).await
 }
 
 pub(super) async fn connect(
     domain: &str,
     port: u16,
-    custom_path: Option<&PathBuf>,
+    config: &TorConfig,
+    timings: &mut ConnectTimings,
 ) -> Result<DataStream, Error> {
-    let client: &TorClient<PreferredRuntime> = get_tor_client(custom_path).await?;
-    Ok(client.connect((domain, port)).await?)
+    // Before the bootstrap, to fail fast
+    check_onion_address(domain)?;
+
+    let start: Instant = Instant::now();
+    let client: TorClient<PreferredRuntime> = get_tor_client(config).await?;
+    timings.tor_bootstrap = Some(start.elapsed());
+
+    // Isolate the stream, if requested
+    let mut prefs: StreamPrefs = StreamPrefs::new();
+    if let Some(token) = config.isolation {
+        prefs.set_isolation(token);
+    }
+
+    let start: Instant = Instant::now();
+    let stream: DataStream = client
+        .connect_with_prefs((domain, port), &prefs)
+        .await
+        .map_err(|source| Error::Connect {
+            domain: domain.to_string(),
+            port,
+            source,
+        })?;
+    timings.tor_circuit = Some(start.elapsed());
+
+    Ok(stream)
+}
+
+/// Check that an onion address is a valid v3 one (subdomains allowed), as the `arti` errors are confusing
+///
+/// The other domains (and IP addresses) are passed through.
+fn check_onion_address(domain: &str) -> Result<(), Error> {
+    let name: &str = match domain.trim_end_matches('.').strip_suffix(".onion") {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+
+    let invalid = |reason: String| Error::InvalidOnionAddress {
+        domain: domain.to_string(),
+        reason,
+    };
+
+    // The service id is the last label
+    let id: &str = name.rsplit('.').next().unwrap_or(name);
+
+    // A longer one may be decoded anyway, with trailing garbage
+    if id.len() != ONION_V3_ID_LEN {
+        return Err(invalid(format!(
+            "the service id must be {ONION_V3_ID_LEN} characters long, not {}",
+            id.len()
+        )));
+    }
+
+    // Check the base32 encoding, the version and the checksum
+    HsId::from_str(&format!("{id}.onion")).map_err(|e| invalid(e.to_string()))?;
+
+    Ok(())
 }
 
 /// Launch onion service and forward requests from `hiddenservice.onion:<port>` to [`SocketAddr`].
+///
+/// The `nickname` must be valid (see [`sanitize_nickname`] to accept arbitrary strings, i.e. from the user input)
+/// or [`Error::InvalidNickname`] is returned.
+///
+/// The service runs on the shared tor client (bootstrapped if needed), the same used by [`connect`](crate::connect),
+/// so the service can be reached from this process too: see [`OnionService::wait_until_running`].
+#[inline]
 #[cfg(feature = "tor-launch-service")]
 pub async fn launch_onion_service<S>(
     nickname: S,
     addr: SocketAddr,
     port: u16,
     custom_path: Option<&PathBuf>,
-) -> Result<Arc<RunningOnionService>, Error>
+) -> Result<OnionService, Error>
 where
     S: Into<String>,
 {
-    // Get tor client
-    let client: &TorClient<PreferredRuntime> = get_tor_client(custom_path).await?;
+    launch_onion_service_with_ports(nickname, [(port, addr)], custom_path).await
+}
 
-    // Configure proxy
-    let mut config: ProxyConfigBuilder = ProxyConfigBuilder::default();
-    let pattern: ProxyPattern = ProxyPattern::one_port(port)?;
-    let action: ProxyAction =
-        ProxyAction::Forward(Encapsulation::default(), TargetAddr::Inet(addr));
-    config.set_proxy_ports(vec![ProxyRule::new(pattern, action)]);
-    let proxy = OnionServiceReverseProxy::new(config.build()?);
+/// Launch onion service and forward requests from every `hiddenservice.onion:<port>` to the related [`SocketAddr`].
+///
+/// The service identity keys are persisted in the state directory, keyed by `nickname`:
+/// launching a service with the same `nickname` and `custom_path` always yields the same onion address.
+///
+/// If the shared tor client is already bootstrapped, it's reused and `custom_path` is ignored.
+#[inline]
+#[cfg(feature = "tor-launch-service")]
+pub async fn launch_onion_service_with_ports<S, I>(
+    nickname: S,
+    ports: I,
+    custom_path: Option<&PathBuf>,
+) -> Result<OnionService, Error>
+where
+    S: Into<String>,
+    I: IntoIterator<Item = (u16, SocketAddr)>,
+{
+    launch_onion_service_with_port_ranges(
+        nickname,
+        ports.into_iter().map(|(port, addr)| (port..=port, addr)),
+        custom_path,
+    )
+    .await
+}
+
+/// Launch onion service and forward requests from every `hiddenservice.onion:<port>`, with `<port>` in the range,
+/// to the related [`SocketAddr`] (i.e. `8000..=8010`).
+///
+/// Returns an error if a range is empty (`start > end`) or includes the port zero.
+/// See [`launch_onion_service_with_ports`] for the details.
+#[cfg(feature = "tor-launch-service")]
+pub async fn launch_onion_service_with_port_ranges<S, I>(
+    nickname: S,
+    ranges: I,
+    custom_path: Option<&PathBuf>,
+) -> Result<OnionService, Error>
+where
+    S: Into<String>,
+    I: IntoIterator<Item = (RangeInclusive<u16>, SocketAddr)>,
+{
+    let proxy = OnionServiceReverseProxy::new(proxy_config(ranges)?);
+
+    // Get tor client
+    let config: TorConfig = TorConfig {
+        custom_path: custom_path.cloned(),
+        ..Default::default()
+    };
+    let client: TorClient<PreferredRuntime> = get_tor_client(&config).await?;
 
     let nickname: HsNickname = HsNickname::new(nickname.into())?;
     let config: OnionServiceConfig = OnionServiceConfigBuilder::default()
         .nickname(nickname.clone())
         .build()?;
 
-    let (service, stream) = client.launch_onion_service(config)?;
+    let (service, stream) = client
+        .launch_onion_service(config)
+        .map_err(Error::LaunchOnionService)?;
+
+    // Get onion hostname from the service identity key
+    let hostname: String = service
+        .onion_name()
+        .ok_or(Error::OnionNameNotFound)?
+        .to_string();
+
+    let proxy_status: Arc<OnceLock<Option<String>>> = Arc::new(OnceLock::new());
+
+    let stopped: Arc<Notify> = Arc::new(Notify::new());
 
-    // TODO: handle error?
     let runtime = client.runtime().clone();
+    let (requests, abort) =
+        future::abortable(async move { proxy.handle_requests(runtime, nickname, stream).await });
+    let status = proxy_status.clone();
+    let notify = stopped.clone();
     client.runtime().spawn(async move {
-        proxy
-            .handle_requests(runtime, nickname, stream)
-            .await
-            .unwrap();
+        match requests.await {
+            // The requests ended, or stopped with `OnionService::stop`
+            Ok(Ok(())) | Err(Aborted) => {
+                let _ = status.set(None);
+            }
+            Ok(Err(e)) => {
+                tracing::error!(error = %e, "Onion service reverse proxy stopped.");
+                let _ = status.set(Some(e.to_string()));
+            }
+        }
+
+        notify.notify_waiters();
     })?;
 
-    Ok(service)
+    Ok(OnionService {
+        service,
+        hostname,
+        proxy: proxy_status,
+        abort,
+        stopped,
+    })
+}
+
+/// Launch onion service and yield the inbound streams, without forwarding them to a [`SocketAddr`].
+///
+/// Every stream opened to `hiddenservice.onion:<port>` is accepted, whatever the port,
+/// and yielded to the caller (i.e. to serve WebSockets with [`accept`](crate::native::accept)).
+/// The requests that aren't data streams are rejected, destroying the circuit.
+///
+/// The service is stopped when both the returned [`RunningOnionService`] and the stream are dropped.
+/// Use [`wait_until_running`] to know when it's reachable. See [`launch_onion_service_with_ports`] for the details.
+#[cfg(feature = "tor-launch-service")]
+pub async fn launch_onion_service_raw<S>(
+    nickname: S,
+    custom_path: Option<&PathBuf>,
+) -> Result<(Arc<RunningOnionService>, impl Stream<Item = DataStream>), Error>
+where
+    S: Into<String>,
+{
+    // Get tor client
+    let config: TorConfig = TorConfig {
+        custom_path: custom_path.cloned(),
+        ..Default::default()
+    };
+    let client: TorClient<PreferredRuntime> = get_tor_client(&config).await?;
+
+    let nickname: HsNickname = HsNickname::new(nickname.into())?;
+    let config: OnionServiceConfig = OnionServiceConfigBuilder::default()
+        .nickname(nickname)
+        .build()?;
+
+    let (service, requests) = client
+        .launch_onion_service(config)
+        .map_err(Error::LaunchOnionService)?;
+
+    Ok((service, accept_streams(requests)))
+}
+
+/// Accept the rendezvous and the data stream requests
+#[cfg(feature = "tor-launch-service")]
+fn accept_streams<S>(requests: S) -> impl Stream<Item = DataStream>
+where
+    S: Stream<Item = RendRequest>,
+{
+    tor_hsservice::handle_rend_requests(requests).filter_map(|request: StreamRequest| async move {
+        if !matches!(request.request(), IncomingStreamRequest::Begin(..)) {
+            tracing::warn!("Rejecting onion service request: not a data stream.");
+            if let Err(e) = request.shutdown_circuit() {
+                tracing::debug!(error = %e, "Can't destroy the onion service circuit.");
+            }
+            return None;
+        }
+
+        match request.accept(Connected::new_empty()).await {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                tracing::debug!(error = %e, "Can't accept the onion service stream.");
+                None
+            }
+        }
+    })
+}
+
+/// Get the onion address of the service with the specified `nickname`, without launching it.
+///
+/// The identity key is read from the state directory (generated and stored if missing),
+/// so the returned address matches the one of [`launch_onion_service`] with the same `nickname` and `custom_path`.
+#[cfg(feature = "tor-launch-service")]
+pub fn onion_address_for<S>(nickname: S, custom_path: Option<&PathBuf>) -> Result<String, Error>
+where
+    S: Into<String>,
+{
+    let config: TorConfig = TorConfig {
+        custom_path: custom_path.cloned(),
+        ..Default::default()
+    };
+    let client_config: TorClientConfig = build_tor_client_config(&config, |_| {})?;
+
+    let nickname: HsNickname = HsNickname::new(nickname.into())?;
+    let config: OnionServiceConfig = OnionServiceConfigBuilder::default()
+        .nickname(nickname)
+        .build()?;
+
+    let service = TorClient::<PreferredRuntime>::create_onion_service(&client_config, config)
+        .map_err(Error::LaunchOnionService)?;
+    Ok(service
+        .onion_name()
+        .ok_or(Error::OnionNameNotFound)?
+        .to_string())
+}
+
+/// Build the proxy config, forwarding the ports in the ranges to the related [`SocketAddr`]
+#[cfg(feature = "tor-launch-service")]
+fn proxy_config<I>(ranges: I) -> Result<ProxyConfig, Error>
+where
+    I: IntoIterator<Item = (RangeInclusive<u16>, SocketAddr)>,
+{
+    let mut config: ProxyConfigBuilder = ProxyConfigBuilder::default();
+    let rules: Vec<ProxyRule> = ranges
+        .into_iter()
+        .map(|(range, addr)| {
+            // `ProxyPattern::port_range` silently replaces the zero start with 1
+            if *range.start() == 0 {
+                return Err(Error::ProxyConfig(ProxyConfigError::ZeroPort));
+            }
+
+            let pattern: ProxyPattern = ProxyPattern::port_range(*range.start(), *range.end())?;
+            let action: ProxyAction =
+                ProxyAction::Forward(Encapsulation::default(), TargetAddr::Inet(addr));
+            Ok(ProxyRule::new(pattern, action))
+        })
+        .collect::<Result<_, Error>>()?;
+    config.set_proxy_ports(rules);
+    Ok(config.build()?)
+}
+
+/// Nicknames forbidden on Windows (device names), rejected on every platform to keep the state directory portable
+#[cfg(feature = "tor-launch-service")]
+const FORBIDDEN_NICKNAMES: [&str; 24] = [
+    "con", "prn", "aux", "nul", "com0", "com1", "com2", "com3", "com4", "com5", "com6", "com7",
+    "com8", "com9", "lpt0", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Map an arbitrary string to a valid onion service nickname
+///
+/// The valid nicknames are made of lowercase ASCII letters, digits, `_` and `-` (not as the first character).
+/// The transformation rules are:
+/// * the uppercase ASCII letters are lowercased;
+/// * any other character (i.e. spaces, punctuation, non-ASCII) and a leading `-` are replaced with `_`;
+/// * an empty string becomes `_`;
+/// * the device names reserved on Windows (i.e. `con`, `nul`, `com1`) get a trailing `_`, on every platform.
+///
+/// The mapping is deterministic (i.e. `My Service!` always becomes `my_service_`), so the onion address
+/// stays the same across launches, but different strings may map to the same nickname.
+#[cfg(feature = "tor-launch-service")]
+pub fn sanitize_nickname(nickname: &str) -> String {
+    let mut sanitized: String = nickname
+        .chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'a'..='z' | '0'..='9' | '_' => c,
+            '-' if i > 0 => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect();
+
+    if sanitized.is_empty() || FORBIDDEN_NICKNAMES.contains(&sanitized.as_str()) {
+        sanitized.push('_');
+    }
+
+    sanitized
+}
+
+/// Generate a random valid onion service nickname (i.e. `service-3f9a0c1d2b4e5f60`)
+///
+/// The identity keys are keyed by nickname, so store it to get the same onion address at the next launch.
+#[cfg(feature = "tor-launch-service")]
+pub fn generate_nickname() -> String {
+    let hasher = RandomState::new().build_hasher();
+    format!("service-{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_eq() {
+        assert_eq!(Error::BootstrapTimeout, Error::BootstrapTimeout);
+        assert_ne!(
+            Error::BootstrapTimeout,
+            Error::from(ConfigBuildError::MissingField {
+                field: String::from("bridges"),
+            })
+        );
+
+        let short = "abc.onion";
+        assert_eq!(check_onion_address(short), check_onion_address(short));
+        assert_ne!(
+            check_onion_address(short),
+            check_onion_address("abcd.onion")
+        );
+        assert!(matches!(
+            check_onion_address(short),
+            Err(Error::InvalidOnionAddress { .. })
+        ));
+    }
+
+    #[test]
+    fn test_config_mismatch() {
+        let shared = TorConfig::default();
+        assert_eq!(config_mismatch(&shared, &shared), None);
+
+        // Not part of the client config
+        let config = TorConfig {
+            isolation: Some(IsolationToken::new()),
+            bootstrap_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        assert_eq!(config_mismatch(&shared, &config), None);
+
+        let config = TorConfig {
+            custom_path: Some(PathBuf::from("/tmp/tor")),
+            ..Default::default()
+        };
+        assert_eq!(config_mismatch(&shared, &config), Some("custom_path"));
+
+        let config = TorConfig {
+            bridges: vec![String::from("obfs4 127.0.0.1:9001")],
+            ..Default::default()
+        };
+        assert_eq!(config_mismatch(&shared, &config), Some("bridges"));
+
+        let config = TorConfig {
+            pt_binary: Some(PathBuf::from("lyrebird")),
+            ..Default::default()
+        };
+        assert_eq!(config_mismatch(&shared, &config), Some("pt_binary"));
+
+        let config = TorConfig {
+            circuit_preemption: CircuitPreemption::Lazy,
+            ..Default::default()
+        };
+        assert_eq!(
+            config_mismatch(&shared, &config),
+            Some("circuit_preemption")
+        );
+    }
+
+    #[test]
+    fn test_check_onion_address() {
+        let id = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid";
+        assert_eq!(check_onion_address(&format!("{id}.onion")), Ok(()));
+        assert_eq!(check_onion_address(&format!("www.{id}.onion")), Ok(()));
+        assert_eq!(check_onion_address(&format!("{id}.onion.")), Ok(()));
+
+        // Not onion addresses
+        assert_eq!(check_onion_address("example.com"), Ok(()));
+        assert_eq!(check_onion_address("127.0.0.1"), Ok(()));
+
+        // Bad checksum
+        let bad_checksum = id.replace("2gzy", "3gzy");
+        assert!(matches!(
+            check_onion_address(&format!("{bad_checksum}.onion")),
+            Err(Error::InvalidOnionAddress { .. })
+        ));
+
+        // Right length, not base32
+        let not_base32 = "1".repeat(ONION_V3_ID_LEN);
+        assert!(matches!(
+            check_onion_address(&format!("{not_base32}.onion")),
+            Err(Error::InvalidOnionAddress { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "tor-launch-service")]
+    fn test_sanitize_nickname() {
+        let cases = [
+            ("service", "service"),
+            ("My Service!", "my_service_"),
+            ("-service", "_service"),
+            ("ser-vice", "ser-vice"),
+            ("sérvice", "s_rvice"),
+            ("", "_"),
+            ("con", "con_"),
+            ("CON", "con_"),
+            ("com1", "com1_"),
+        ];
+
+        for (nickname, expected) in cases {
+            let sanitized: String = sanitize_nickname(nickname);
+            assert_eq!(sanitized, expected);
+            assert!(HsNickname::new(sanitized).is_ok());
+        }
+
+        assert!(HsNickname::new(generate_nickname()).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "tor-launch-service")]
+    #[allow(clippy::reversed_empty_ranges)]
+    fn test_proxy_config() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        assert!(proxy_config([(80..=80, addr), (8000..=8010, addr)]).is_ok());
+
+        for range in [0..=0, 0..=80, 80..=0] {
+            assert_eq!(
+                proxy_config([(range, addr)]).unwrap_err(),
+                Error::ProxyConfig(ProxyConfigError::ZeroPort)
+            );
+        }
+
+        assert_eq!(
+            proxy_config([(80..=80, addr), (8010..=8000, addr)]).unwrap_err(),
+            Error::ProxyConfig(ProxyConfigError::EmptyPortRange)
+        );
+    }
+
+    #[test]
+    fn test_invalid_bridge_line_eq() {
+        let config = TorConfig {
+            bridges: vec![String::from("not a bridge")],
+            ..Default::default()
+        };
+        let err: Error = build_tor_client_config(&config, |_| ()).unwrap_err();
+        assert!(matches!(err, Error::InvalidBridgeLine(..)));
+        assert_eq!(err, build_tor_client_config(&config, |_| ()).unwrap_err());
+    }
 }
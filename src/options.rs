@@ -0,0 +1,579 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection options
+
+use std::net::SocketAddr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+use tokio_rustls::rustls::pki_types::pem::{self, PemObject};
+#[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+use tokio_rustls::rustls::pki_types::CertificateDer;
+#[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+use tokio_rustls::rustls::ClientConfig;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::native::DnsResolver;
+#[cfg(target_arch = "wasm32")]
+use crate::wasm::BinaryType;
+#[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+use crate::Error;
+
+/// Default connection timeout
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Default max message size (64 MiB)
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+/// Default max frame size (16 MiB)
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 << 20;
+/// Default write buffer size (128 KiB, as `tungstenite`)
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 128 << 10;
+/// Default timeout of the closing handshake
+pub(crate) const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to do when the send buffer is full
+///
+/// See [`ConnectionOptions::send_buffer_overflow`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OverflowPolicy {
+    /// Wait for space in the buffer
+    #[default]
+    Block,
+    /// Drop the new message
+    DropNewest,
+    /// Drop the oldest queued message, to make space for the new one
+    DropOldest,
+    /// Fail with `Error::SendBufferFull`, dropping the new message
+    Error,
+}
+
+/// Connection options
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub(crate) timeout: Duration,
+    pub(crate) handshake_timeout: Option<Duration>,
+    pub(crate) close_timeout: Duration,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) host_override: Option<String>,
+    pub(crate) subprotocols: Vec<String>,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) max_frame_size: Option<usize>,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) max_write_buffer_size: Option<usize>,
+    pub(crate) keepalive: Option<(Duration, Duration)>,
+    pub(crate) max_unanswered_pings: Option<usize>,
+    pub(crate) idle_timeout: Option<(Duration, bool)>,
+    pub(crate) send_buffer: Option<usize>,
+    pub(crate) send_buffer_overflow: OverflowPolicy,
+    pub(crate) receive_buffer: Option<usize>,
+    pub(crate) message_rate: Option<(u32, u32)>,
+    pub(crate) byte_rate: Option<(u64, u64)>,
+    pub(crate) max_redirects: usize,
+    pub(crate) auto_route_onion: bool,
+    pub(crate) nodelay: bool,
+    pub(crate) tcp_keepalive: Option<(Duration, Duration, u32)>,
+    pub(crate) bind_addr: Option<SocketAddr>,
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub(crate) bind_device: Option<String>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) dns_resolver: Option<Arc<dyn DnsResolver>>,
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) binary_type: BinaryType,
+    #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+    pub(crate) tls_config: Option<Arc<ClientConfig>>,
+    #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+    pub(crate) alpn_protocols: Vec<Vec<u8>>,
+    #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+    pub(crate) root_certificates: Vec<CertificateDer<'static>>,
+    #[cfg(all(feature = "dangerous-tls", not(target_arch = "wasm32")))]
+    pub(crate) danger_accept_invalid_certs: bool,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            handshake_timeout: None,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            headers: Vec::new(),
+            host_override: None,
+            subprotocols: Vec::new(),
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            max_frame_size: Some(DEFAULT_MAX_FRAME_SIZE),
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            max_write_buffer_size: None,
+            keepalive: None,
+            max_unanswered_pings: None,
+            idle_timeout: None,
+            send_buffer: None,
+            send_buffer_overflow: OverflowPolicy::Block,
+            receive_buffer: None,
+            message_rate: None,
+            byte_rate: None,
+            max_redirects: 0,
+            auto_route_onion: true,
+            nodelay: true,
+            tcp_keepalive: None,
+            bind_addr: None,
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            bind_device: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            dns_resolver: None,
+            #[cfg(target_arch = "wasm32")]
+            binary_type: BinaryType::default(),
+            #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+            tls_config: None,
+            #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+            alpn_protocols: vec![b"http/1.1".to_vec()],
+            #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+            root_certificates: Vec::new(),
+            #[cfg(all(feature = "dangerous-tls", not(target_arch = "wasm32")))]
+            danger_accept_invalid_certs: false,
+        }
+    }
+}
+
+impl From<Duration> for ConnectionOptions {
+    /// Default connection options, with a custom timeout
+    #[inline]
+    fn from(timeout: Duration) -> Self {
+        Self::new().timeout(timeout)
+    }
+}
+
+impl ConnectionOptions {
+    /// New default connection options
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set connection timeout (default: 60 secs)
+    ///
+    /// The timeout covers the whole connection process: TCP connection, proxy negotiation,
+    /// tor bootstrap (in tor mode), TLS and WebSocket handshakes.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the timeout of the WebSocket handshake (default: only the connection timeout)
+    ///
+    /// It covers only the HTTP upgrade exchange, after the TCP connection and the TLS handshake: a server
+    /// accepting the connection but never answering the upgrade fails with [`Error::HandshakeTimeout`](crate::Error::HandshakeTimeout).
+    /// The connection timeout still applies.
+    ///
+    /// Ignored on WASM: the handshake is performed by the browser.
+    #[inline]
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the timeout of the closing handshake (default: 10 secs)
+    ///
+    /// How long `Sink::close_with` waits for the close frame of the server (or the end of the connection):
+    /// on expiry, it fails with `Error::Timeout`. The background task (if any) waits as long to write its
+    /// close frames (i.e. answering an invalid frame of the server).
+    #[inline]
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// Add an HTTP header to the WebSocket handshake request
+    ///
+    /// The headers managed by the handshake (`Host`, `Upgrade`, `Connection` and `Sec-WebSocket-*`)
+    /// are ignored, to avoid breaking it.
+    ///
+    /// Not supported on WASM: the browser WebSocket API doesn't allow custom headers.
+    #[inline]
+    pub fn header<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set the host to present in the handshake request, instead of the one of the URL (default: `None`)
+    ///
+    /// The host is used for the `Host` header (with the port of the URL, if not the default one)
+    /// and for the TLS SNI and certificate verification, while the connection still goes to the host of the URL.
+    /// Useful to connect to a specific IP address of a virtual host. It must be a valid host (without port):
+    /// the connection fails with a `Url` error otherwise. Not applied to the redirect locations.
+    ///
+    /// Not supported on WASM: the browser WebSocket API doesn't allow custom headers.
+    #[inline]
+    pub fn host_override(mut self, host: Option<String>) -> Self {
+        self.host_override = host;
+        self
+    }
+
+    /// Set the subprotocols to request during the handshake, in order of preference
+    ///
+    /// An empty list (the default) omits the `Sec-WebSocket-Protocol` header, as do the empty names.
+    /// The connection fails if the server selects a subprotocol not included in the list
+    /// or, on native, if it selects none while some are requested.
+    /// The negotiated one is available via `Sink::protocol` and `Stream::protocol`.
+    #[inline]
+    pub fn subprotocols<I, S>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.subprotocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the max size of an incoming message (default: 64 MiB)
+    ///
+    /// `None` means no limit. Receiving a bigger message returns a `MessageTooLarge` error.
+    /// An incoming message is fully buffered (its frames reassembled) before being returned,
+    /// so this is also the max memory used by a message being received.
+    ///
+    /// Compression (`permessage-deflate`) is never negotiated (the `Sec-WebSocket-Extensions` header
+    /// can't be set, see [`ConnectionOptions::header`]): the received payloads are never inflated,
+    /// so this limit also bounds the bytes read from the socket for a message.
+    ///
+    /// Not supported on WASM: the limits are managed by the browser.
+    #[inline]
+    pub fn max_message_size(mut self, size: Option<usize>) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Set the max size of a single incoming frame payload (default: 16 MiB)
+    ///
+    /// `None` means no limit. Receiving a bigger frame returns a `MessageTooLarge` error.
+    ///
+    /// Not supported on WASM: the limits are managed by the browser.
+    #[inline]
+    pub fn max_frame_size(mut self, size: Option<usize>) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// Set the size of the write buffer, to coalesce the small writes (default: 128 KiB)
+    ///
+    /// The outgoing frames are written to the socket only when the buffer grows past this size, or on flush.
+    /// `0` writes every frame immediately (lower latency), a bigger buffer means fewer syscalls (higher throughput).
+    ///
+    /// Not supported on WASM: the writes are managed by the browser.
+    #[inline]
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// Set the max size of the write buffer (default: no limit)
+    ///
+    /// `None` means no limit. When the socket can't keep up and the buffer would exceed this size,
+    /// the send fails with a `WriteBufferFull` error.
+    /// Must be greater than the [`ConnectionOptions::write_buffer_size`] (`tungstenite` requires it),
+    /// otherwise the connection fails with an `InvalidWriteBufferSize` error.
+    ///
+    /// Not supported on WASM: the writes are managed by the browser.
+    #[inline]
+    pub fn max_write_buffer_size(mut self, size: Option<usize>) -> Self {
+        self.max_write_buffer_size = size;
+        self
+    }
+
+    /// Enable ping/pong keepalive (default: disabled)
+    ///
+    /// A ping is sent every `interval`: if the pong isn't received within `timeout`,
+    /// the connection is closed and the stream returns a `KeepaliveTimeout` error.
+    /// The pings are sent by a background task, so the connection is kept alive even when idle.
+    /// The time of the last pong is available via `Sink::last_pong` and `Stream::last_pong`.
+    /// It can be changed while connected with `Sink::set_keepalive`.
+    ///
+    /// Ignored on WASM: the browser WebSocket API doesn't expose ping frames.
+    #[inline]
+    pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keepalive = Some((interval, timeout));
+        self
+    }
+
+    /// Send the keepalive pings every interval, without waiting for the pongs, up to `max` unanswered ones
+    /// (default: disabled, `0` is handled as `1`)
+    ///
+    /// By default, the next ping is sent only after the pong of the previous one. With this set, the connection
+    /// is also closed with a `KeepaliveTimeout` error when a ping is due while `max` pings are still unanswered,
+    /// catching a slow degradation before the pong timeout. The current count is in [`ConnectionStats::unanswered_pings`](crate::ConnectionStats::unanswered_pings).
+    ///
+    /// Ignored on WASM: the browser WebSocket API doesn't expose ping frames.
+    #[inline]
+    pub fn max_unanswered_pings(mut self, max: usize) -> Self {
+        self.max_unanswered_pings = Some(max.max(1));
+        self
+    }
+
+    /// Close the connection if no frame is received within `timeout` (default: disabled)
+    ///
+    /// Any received frame (data, ping, pong or close) resets the timer. If `include_sent` is true,
+    /// the messages sent through `Sink` reset it too (the keepalive pings don't).
+    /// On expiry, the connection is closed and the stream returns an `IdleTimeout` error.
+    /// Like keepalive, it's handled by a background task, and it can be changed with `Sink::set_idle_timeout`.
+    ///
+    /// Ignored on WASM: the browser WebSocket API doesn't expose the control frames.
+    #[inline]
+    pub fn idle_timeout(mut self, timeout: Duration, include_sent: bool) -> Self {
+        self.idle_timeout = Some((timeout, include_sent));
+        self
+    }
+
+    /// Buffer the outgoing messages in a bounded queue, drained by a background task (default: disabled)
+    ///
+    /// Allows to queue messages without waiting with `Sink::try_send`, which fails when `capacity` messages
+    /// (at least 1) are queued.
+    /// If keepalive (or the idle timeout) is enabled, the queue is always used (with a default capacity of 32 messages).
+    ///
+    /// Not supported on WASM: the browser buffers the outgoing messages.
+    #[inline]
+    pub fn send_buffer(mut self, capacity: usize) -> Self {
+        self.send_buffer = Some(capacity);
+        self
+    }
+
+    /// Set what to do when the send buffer is full (default: [`OverflowPolicy::Block`])
+    ///
+    /// With the drop policies, the dropped messages are counted in [`ConnectionStats::messages_dropped`](crate::ConnectionStats::messages_dropped)
+    /// (and not as sent). `Sink::try_send` applies the drop policies too.
+    ///
+    /// The policy applies only to the text and binary messages: the control frames (close, ping and pong)
+    /// are always queued, even if the buffer is full, so the closing handshake is never lost.
+    ///
+    /// Ignored without the send buffer (see [`ConnectionOptions::send_buffer`]) and on WASM.
+    #[inline]
+    pub fn send_buffer_overflow(mut self, policy: OverflowPolicy) -> Self {
+        self.send_buffer_overflow = policy;
+        self
+    }
+
+    /// Set the max number of received messages queued by the background task for the stream (default: 32, at least 1)
+    ///
+    /// When the queue is full, the task stops reading from the socket until the stream is polled:
+    /// the TCP flow control then slows the server down, no message is dropped.
+    /// While waiting, the task doesn't write the queued messages or the keepalive pings either.
+    /// The memory used is bounded by `capacity` messages (see [`ConnectionOptions::max_message_size`]).
+    ///
+    /// Ignored without the background task (keepalive, idle timeout or send buffer): the messages are read
+    /// from the socket only when the stream is polled. Not supported on WASM: the browser buffers the incoming messages.
+    #[inline]
+    pub fn receive_buffer(mut self, capacity: usize) -> Self {
+        self.receive_buffer = Some(capacity);
+        self
+    }
+
+    /// Limit the outgoing messages to `per_second`, allowing bursts of up to `burst` messages (default: disabled)
+    ///
+    /// Token bucket: when the bucket is empty, `Sink::send` waits for the refill (and `Sink::try_send`
+    /// returns `TrySendError::RateLimited`). All the messages sent through `Sink` count, the close frames included.
+    /// The rate and the burst are at least 1.
+    ///
+    /// Not supported on WASM.
+    #[inline]
+    pub fn rate_limit_messages(mut self, per_second: u32, burst: u32) -> Self {
+        self.message_rate = Some((per_second, burst));
+        self
+    }
+
+    /// Limit the outgoing payload bytes to `per_second`, allowing bursts of up to `burst` bytes (default: disabled)
+    ///
+    /// Like [`ConnectionOptions::rate_limit_messages`], and can be combined with it.
+    /// A message bigger than the available bytes is sent anyway: the next messages wait until the deficit is refilled.
+    ///
+    /// Not supported on WASM.
+    #[inline]
+    pub fn rate_limit_bytes(mut self, per_second: u64, burst: u64) -> Self {
+        self.byte_rate = Some((per_second, burst));
+        self
+    }
+
+    /// Follow up to `max` redirects (3xx responses) during the WebSocket handshake (default: 0, disabled)
+    ///
+    /// The connection is re-established to the `Location` URL, with the same mode and options.
+    /// The custom headers (see [`ConnectionOptions::header`]) aren't sent if the scheme, the host or the port
+    /// differs from the ones of the initial URL, to not leak the credentials (i.e. `Authorization` or `Cookie`).
+    /// The redirects from `wss` to `ws` (or `http`) and the redirect loops are rejected.
+    ///
+    /// Ignored on WASM: the browser WebSocket API doesn't follow the redirects.
+    #[inline]
+    pub fn max_redirects(mut self, max: usize) -> Self {
+        self.max_redirects = max;
+        self
+    }
+
+    /// Route the `.onion` hosts through the embedded tor client in direct mode (default: true)
+    ///
+    /// The default `TorConfig` is used: on `android` and `ios`, use the tor mode instead,
+    /// to set the data path. Without the `tor` feature, connecting to an onion address in direct mode fails
+    /// with `Error::OnionRequiresTor`.
+    ///
+    /// Disable it to resolve the `.onion` hosts as the other ones (i.e. with a tor transparent proxy or
+    /// a custom DNS resolver). Ignored in the other modes and on WASM.
+    #[inline]
+    pub fn auto_route_onion(mut self, enable: bool) -> Self {
+        self.auto_route_onion = enable;
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the TCP connections (default: true)
+    ///
+    /// Disables the Nagle's algorithm, so the small frames are sent without delay.
+    ///
+    /// Not applied in tor mode and on WASM.
+    #[inline]
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Enable the OS TCP keepalive (default: disabled)
+    ///
+    /// The first probe is sent after `idle`, then every `interval`: the connection is dropped after `retries` unanswered probes.
+    /// `interval` and `retries` are ignored on the platforms that don't support them (i.e. `retries` on Windows).
+    ///
+    /// Not applied in tor mode and on WASM. To detect dead connections on every platform, use [`ConnectionOptions::keepalive`].
+    #[inline]
+    pub fn tcp_keepalive(mut self, idle: Duration, interval: Duration, retries: u32) -> Self {
+        self.tcp_keepalive = Some((idle, interval, retries));
+        self
+    }
+
+    /// Bind the outbound TCP connections to a local address (i.e. `192.168.1.10:0`)
+    ///
+    /// In direct mode, only the resolved addresses of the same IP family are tried.
+    /// In proxy mode, the connection to the proxy is bound.
+    ///
+    /// Not supported in tor mode and on WASM: the connection fails with a `BindNotSupported` error.
+    #[inline]
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind_addr = Some(addr);
+        self
+    }
+
+    /// Bind the outbound TCP connections to a network interface (i.e. `eth0`), using `SO_BINDTODEVICE`
+    ///
+    /// Usually requires the `CAP_NET_RAW` capability.
+    ///
+    /// Not supported in tor mode: the connection fails with a `BindNotSupported` error.
+    #[inline]
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn bind_device<S>(mut self, interface: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.bind_device = Some(interface.into());
+        self
+    }
+
+    /// Use a custom DNS resolver for the direct connections (default: system resolver)
+    ///
+    /// The connection attempts to the resolved addresses are raced (happy eyeballs, RFC 8305),
+    /// so an unreachable address doesn't stall the connection.
+    ///
+    /// Ignored in proxy and tor modes: the host is resolved by the proxy or by the tor network.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn dns_resolver(mut self, resolver: Arc<dyn DnsResolver>) -> Self {
+        self.dns_resolver = Some(resolver);
+        self
+    }
+
+    /// Set how the browser delivers the binary messages (default: [`BinaryType::ArrayBuffer`])
+    ///
+    /// WASM only.
+    #[inline]
+    #[cfg(target_arch = "wasm32")]
+    pub fn binary_type(mut self, binary_type: BinaryType) -> Self {
+        self.binary_type = binary_type;
+        self
+    }
+
+    /// Use a custom `rustls` client config
+    ///
+    /// Takes precedence over the root certificates added with [`ConnectionOptions::add_root_certificate`].
+    /// The connections use `rustls`, even if the `native-tls` backend is enabled too.
+    #[inline]
+    #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+    pub fn tls_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.tls_config = Some(config);
+        self
+    }
+
+    /// Set the ALPN protocols of the TLS handshake, in order of preference (default: `http/1.1`)
+    ///
+    /// Some load balancers route the connections on the negotiated protocol: the WebSocket upgrade
+    /// requires `http/1.1`. An empty list disables ALPN. A server rejecting all the protocols fails
+    /// the TLS handshake.
+    ///
+    /// Ignored with a custom config (see [`ConnectionOptions::tls_config`]) and with the `native-tls` backend.
+    #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+    pub fn alpn_protocols<I, P>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Vec<u8>>,
+    {
+        self.alpn_protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Trust an additional root certificate (i.e. a private CA)
+    ///
+    /// The default roots are kept: the `webpki` ones, or the OS trust store with the `native-tls` backend.
+    #[inline]
+    #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+    pub fn add_root_certificate(mut self, cert: CertificateDer<'static>) -> Self {
+        self.root_certificates.push(cert);
+        self
+    }
+
+    /// Trust the additional root certificates of a PEM file content
+    ///
+    /// Return an error if the PEM is invalid or doesn't contain any certificate.
+    #[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+    pub fn add_root_certificates_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        let len: usize = self.root_certificates.len();
+
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            self.root_certificates.push(cert?);
+        }
+
+        if self.root_certificates.len() == len {
+            return Err(Error::InvalidPem(pem::Error::NoItemsFound));
+        }
+
+        Ok(self)
+    }
+
+    /// **DANGER**: accept any TLS certificate (default: false)
+    ///
+    /// Disables the certificate verification, making the connection vulnerable to MITM attacks.
+    /// Use ONLY for testing (i.e. with self-signed dev servers).
+    ///
+    /// Ignored if a custom config is set with [`ConnectionOptions::tls_config`].
+    #[inline]
+    #[cfg(all(feature = "dangerous-tls", not(target_arch = "wasm32")))]
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.danger_accept_invalid_certs = accept;
+        self
+    }
+
+    /// Check if the connections must be bound to a local address or device
+    #[inline]
+    #[cfg(any(feature = "tor", target_arch = "wasm32"))]
+    pub(crate) fn is_bound(&self) -> bool {
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if self.bind_device.is_some() {
+            return true;
+        }
+
+        self.bind_addr.is_some()
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Data message payload
+
+use bytes::Bytes;
+use futures_util::{Stream as StreamTrait, StreamExt};
+
+use crate::{Error, WsMessage};
+
+/// Payload of a data message
+///
+/// The binary payload is moved into [`Bytes`] without copying (`Bytes::from(Vec<u8>)` takes
+/// ownership of the buffer filled by the WebSocket implementation), so it can be sliced and shared
+/// with the downstream parsers for free. Compared to `Bytes::copy_from_slice`, this saves one
+/// allocation and one copy of the whole payload for each message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Payload {
+    /// Text message
+    Text(String),
+    /// Binary message
+    Binary(Bytes),
+}
+
+impl Payload {
+    /// Extract the payload of a data message, or `None` for the control messages
+    pub(crate) fn from_message(msg: WsMessage) -> Option<Self> {
+        match msg {
+            WsMessage::Text(text) => Some(Self::Text(text)),
+            WsMessage::Binary(data) => Some(Self::Binary(Bytes::from(data))),
+            #[cfg(not(target_arch = "wasm32"))]
+            _ => None,
+        }
+    }
+
+    /// Get the length of the payload
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Text(text) => text.len(),
+            Self::Binary(data) => data.len(),
+        }
+    }
+
+    /// Check if the payload is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Consume the payload and return it as bytes (without copying)
+    #[inline]
+    pub fn into_bytes(self) -> Bytes {
+        match self {
+            Self::Text(text) => Bytes::from(text),
+            Self::Binary(data) => data,
+        }
+    }
+}
+
+/// Wait for the next data message, skipping the control messages
+pub(crate) async fn next_payload<S>(stream: &mut S) -> Option<Result<Payload, Error>>
+where
+    S: StreamTrait<Item = Result<WsMessage, Error>> + Unpin,
+{
+    loop {
+        match stream.next().await? {
+            Ok(msg) => {
+                if let Some(payload) = Payload::from_message(msg) {
+                    return Some(Ok(payload));
+                }
+            }
+            Err(e) => return Some(Err(e)),
+        }
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection pool
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use url::Url;
+
+use crate::native::runtime;
+use crate::{ConnectionMode, ConnectionOptions, Error, Sink, Stream, WsMessage};
+
+/// Default max connections for each URL and mode
+const DEFAULT_MAX_PER_HOST: usize = 4;
+/// Default time after which an idle connection is closed
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Default time to wait for the pong of the health check
+const DEFAULT_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Key = (Url, ConnectionMode);
+type Hosts = Arc<Mutex<HashMap<Key, Host>>>;
+
+/// Idle connection
+struct Idle {
+    sink: Sink,
+    stream: Stream,
+    /// When the connection is evicted
+    deadline: Instant,
+}
+
+#[derive(Default)]
+struct Host {
+    /// Permits for the connections in use or idle
+    permits: Option<Arc<Semaphore>>,
+    idle: Vec<Idle>,
+}
+
+/// Pool of connections, keyed by URL and [`ConnectionMode`]
+///
+/// [`ConnectionPool::get`] hands out an idle connection, if any passes the health check (a ping answered
+/// within the timeout), or opens a new one. When done, [`PooledConnection::release`] gives it back to the pool:
+/// dropping it closes the connection instead.
+///
+/// Share it between tasks with an [`Arc`].
+pub struct ConnectionPool {
+    opts: ConnectionOptions,
+    max_per_host: usize,
+    idle_timeout: Duration,
+    health_check_timeout: Duration,
+    hosts: Hosts,
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("max_per_host", &self.max_per_host)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("health_check_timeout", &self.health_check_timeout)
+            .finish()
+    }
+}
+
+impl ConnectionPool {
+    /// New pool, opening the connections with `opts`
+    pub fn new(opts: ConnectionOptions) -> Self {
+        Self {
+            opts,
+            max_per_host: DEFAULT_MAX_PER_HOST,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            health_check_timeout: DEFAULT_HEALTH_CHECK_TIMEOUT,
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Set the max connections (in use and idle) for each URL and mode (default: 4, at least 1)
+    ///
+    /// When reached, [`ConnectionPool::get`] waits for a connection to be released or dropped.
+    #[inline]
+    pub fn max_per_host(mut self, max: usize) -> Self {
+        self.max_per_host = max.max(1);
+        self
+    }
+
+    /// Set after how long an idle connection is closed (default: 60 secs)
+    #[inline]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Set how long to wait for the pong of the health check, before discarding an idle connection (default: 5 secs)
+    #[inline]
+    pub fn health_check_timeout(mut self, timeout: Duration) -> Self {
+        self.health_check_timeout = timeout;
+        self
+    }
+
+    /// Get a connection to `url`, reusing an idle one if healthy
+    ///
+    /// The messages received while the connection was idle are discarded.
+    pub async fn get(&self, url: &Url, mode: &ConnectionMode) -> Result<PooledConnection, Error> {
+        let key: Key = (url.clone(), mode.clone());
+        let permit: OwnedSemaphorePermit = self
+            .permits(&key)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+
+        while let Some(mut idle) = self.take_idle(&key) {
+            if is_healthy(&mut idle.sink, &mut idle.stream, self.health_check_timeout).await {
+                tracing::debug!(id = idle.sink.id(), "Reusing pooled connection to {url}.");
+                return Ok(self.pooled(key, idle.sink, idle.stream, permit));
+            }
+
+            tracing::debug!(
+                id = idle.sink.id(),
+                "Discarding unhealthy pooled connection."
+            );
+        }
+
+        let (sink, stream) = crate::connect(url, mode, self.opts.clone()).await?;
+        Ok(self.pooled(key, sink, stream, permit))
+    }
+
+    /// Close the idle connections past the idle timeout
+    ///
+    /// The expired connections are also evicted when the pool is used.
+    pub fn evict_idle(&self) {
+        let now: Instant = Instant::now();
+        for host in lock(&self.hosts).values_mut() {
+            host.idle.retain(|idle| idle.deadline > now);
+        }
+    }
+
+    /// Get the number of idle connections
+    pub fn idle_count(&self) -> usize {
+        lock(&self.hosts).values().map(|host| host.idle.len()).sum()
+    }
+
+    fn permits(&self, key: &Key) -> Arc<Semaphore> {
+        let mut hosts = lock(&self.hosts);
+        let host: &mut Host = hosts.entry(key.clone()).or_default();
+        host.permits
+            .get_or_insert_with(|| Arc::new(Semaphore::new(self.max_per_host)))
+            .clone()
+    }
+
+    /// Take the most recently used idle connection, evicting the expired ones
+    fn take_idle(&self, key: &Key) -> Option<Idle> {
+        let now: Instant = Instant::now();
+        let mut hosts = lock(&self.hosts);
+        let host: &mut Host = hosts.get_mut(key)?;
+        host.idle.retain(|idle| idle.deadline > now);
+        host.idle.pop()
+    }
+
+    fn pooled(
+        &self,
+        key: Key,
+        sink: Sink,
+        stream: Stream,
+        permit: OwnedSemaphorePermit,
+    ) -> PooledConnection {
+        PooledConnection {
+            sink,
+            stream,
+            key,
+            hosts: self.hosts.clone(),
+            idle_timeout: self.idle_timeout,
+            permit,
+        }
+    }
+}
+
+/// Connection handed out by a [`ConnectionPool`]
+pub struct PooledConnection {
+    sink: Sink,
+    stream: Stream,
+    key: Key,
+    hosts: Hosts,
+    idle_timeout: Duration,
+    permit: OwnedSemaphorePermit,
+}
+
+impl fmt::Debug for PooledConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledConnection")
+            .field("id", &self.sink.id())
+            .field("url", &self.key.0)
+            .field("mode", &self.key.1)
+            .finish()
+    }
+}
+
+impl PooledConnection {
+    /// Get the sending half
+    #[inline]
+    pub fn sink(&mut self) -> &mut Sink {
+        &mut self.sink
+    }
+
+    /// Get the receiving half
+    #[inline]
+    pub fn stream(&mut self) -> &mut Stream {
+        &mut self.stream
+    }
+
+    /// Get both halves
+    #[inline]
+    pub fn split(&mut self) -> (&mut Sink, &mut Stream) {
+        (&mut self.sink, &mut self.stream)
+    }
+
+    /// Give the connection back to the pool, to be reused
+    ///
+    /// If the connection is closed, it's dropped.
+    pub fn release(self) {
+        if !self.sink.state().is_connected() {
+            return;
+        }
+
+        let idle: Idle = Idle {
+            sink: self.sink,
+            stream: self.stream,
+            deadline: Instant::now() + self.idle_timeout,
+        };
+
+        lock(&self.hosts)
+            .entry(self.key)
+            .or_default()
+            .idle
+            .push(idle);
+
+        // Only now, so a waiting `get` finds the connection
+        drop(self.permit);
+    }
+
+    /// Take the connection out of the pool, freeing its slot
+    #[inline]
+    pub fn into_inner(self) -> (Sink, Stream) {
+        (self.sink, self.stream)
+    }
+}
+
+/// Send a ping and wait for the pong, discarding the other messages
+async fn is_healthy(sink: &mut Sink, stream: &mut Stream, timeout: Duration) -> bool {
+    if !sink.state().is_connected() {
+        return false;
+    }
+
+    // The connection id is a payload not used by the keepalive pings
+    let nonce: Vec<u8> = sink.id().to_be_bytes().to_vec();
+
+    if sink.send(WsMessage::Ping(nonce.clone())).await.is_err() {
+        return false;
+    }
+
+    let pong = async {
+        while let Some(Ok(msg)) = stream.next().await {
+            if let WsMessage::Pong(payload) = msg {
+                if payload == nonce {
+                    return true;
+                }
+            }
+        }
+
+        false
+    };
+
+    runtime::timeout(timeout, pong).await.unwrap_or(false)
+}
+
+fn lock(hosts: &Hosts) -> MutexGuard<'_, HashMap<Key, Host>> {
+    match hosts.lock() {
+        Ok(hosts) => hosts,
+        Err(e) => e.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::test_util::EchoServer;
+
+    fn url(addr: SocketAddr) -> Url {
+        Url::parse(&format!("ws://{addr}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reuse() {
+        let (addr, _server) = EchoServer::new().spawn().await;
+        let url: Url = url(addr);
+        let pool = ConnectionPool::new(ConnectionOptions::new());
+
+        let mut conn = pool.get(&url, &ConnectionMode::Direct).await.unwrap();
+        let id: u64 = conn.sink().id();
+        conn.release();
+        assert_eq!(pool.idle_count(), 1);
+
+        let mut conn = pool.get(&url, &ConnectionMode::Direct).await.unwrap();
+        assert_eq!(conn.sink().id(), id);
+        assert_eq!(pool.idle_count(), 0);
+
+        // Still usable after the health check
+        let msg = WsMessage::Text(String::from("hello"));
+        conn.sink().send(msg.clone()).await.unwrap();
+        assert_eq!(conn.stream().next().await.unwrap().unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn test_evict_idle() {
+        let (addr, _server) = EchoServer::new().spawn().await;
+        let pool =
+            ConnectionPool::new(ConnectionOptions::new()).idle_timeout(Duration::from_millis(50));
+
+        let conn = pool.get(&url(addr), &ConnectionMode::Direct).await.unwrap();
+        conn.release();
+
+        pool.evict_idle();
+        assert_eq!(pool.idle_count(), 1);
+
+        runtime::sleep(Duration::from_millis(100)).await;
+        pool.evict_idle();
+        assert_eq!(pool.idle_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unhealthy_idle() {
+        // The server drops the connection after the first echo, while it's idle in the pool
+        let (addr, _server) = EchoServer::new().drop_after(1).spawn().await;
+        let url: Url = url(addr);
+        let pool = ConnectionPool::new(ConnectionOptions::new());
+
+        let mut conn = pool.get(&url, &ConnectionMode::Direct).await.unwrap();
+        let id: u64 = conn.sink().id();
+        let msg = WsMessage::Text(String::from("hello"));
+        conn.sink().send(msg.clone()).await.unwrap();
+        assert_eq!(conn.stream().next().await.unwrap().unwrap(), msg);
+        conn.release();
+
+        // Not noticed until the health check
+        assert_eq!(pool.idle_count(), 1);
+
+        let mut conn = pool.get(&url, &ConnectionMode::Direct).await.unwrap();
+        assert_ne!(conn.sink().id(), id);
+        assert_eq!(pool.idle_count(), 0);
+
+        conn.sink().send(msg.clone()).await.unwrap();
+        assert_eq!(conn.stream().next().await.unwrap().unwrap(), msg);
+    }
+}
@@ -0,0 +1,431 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Reconnecting WebSocket
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_util::{future, ready, SinkExt, Stream as StreamTrait, StreamExt};
+use tokio::sync::broadcast;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_tungstenite::tungstenite::Error as WsError;
+use url::Url;
+
+use crate::state::StateNotifier;
+use crate::{
+    ConnectionMode, ConnectionOptions, ConnectionState, DisconnectReason, Error, RetryConfig, Sink,
+    Stream, WsMessage,
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+type ConnectFuture = Pin<Box<dyn Future<Output = Result<(Sink, Stream), Error>> + Send>>;
+#[cfg(target_arch = "wasm32")]
+type ConnectFuture = Pin<Box<dyn Future<Output = Result<(Sink, Stream), Error>>>>;
+
+type Resubscribe = Arc<dyn Fn() -> Vec<WsMessage> + Send + Sync>;
+
+/// Reconnecting WebSocket event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Message received
+    Message(WsMessage),
+    /// The connection has been re-established (and the resubscribe messages sent)
+    Reconnected,
+}
+
+/// WebSocket that transparently re-establishes the connection when it drops
+///
+/// The connection is re-established (see [`crate::connect_with_retry`]) when it's closed by the server
+/// or fails with a transient error. After each connection, the messages returned by the `resubscribe`
+/// callback are sent, so subscriptions can be restored.
+///
+/// The stream ends only after a fatal error (returned as the last item), when the retry attempts
+/// (or the [`RetryConfig::max_total_connect_duration`] of each reconnection) are exhausted or after [`ReconnectingWebSocket::close`].
+///
+/// The connection state transitions (`Connected` -> `Disconnected` -> `Connecting` -> `Connected`)
+/// can be observed with [`ReconnectingWebSocket::state_events`].
+///
+/// To not wait for the first connection on creation, use [`ReconnectingWebSocket::new`]
+/// and [`ReconnectingWebSocket::await_connected`].
+pub struct ReconnectingWebSocket {
+    url: Url,
+    mode: ConnectionMode,
+    opts: ConnectionOptions,
+    retry: RetryConfig,
+    resubscribe: Resubscribe,
+    sink: Option<Sink>,
+    stream: Option<Stream>,
+    reconnecting: Option<ConnectFuture>,
+    state: StateNotifier,
+    /// The first connection has been established
+    connected_once: bool,
+    closed: bool,
+}
+
+impl fmt::Debug for ReconnectingWebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingWebSocket")
+            .field("url", &self.url)
+            .field("mode", &self.mode)
+            .field("state", &self.state.get())
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+impl ReconnectingWebSocket {
+    /// Connect, sending the messages returned by `resubscribe` after each connection
+    ///
+    /// Waits for the first connection: see [`ReconnectingWebSocket::new`] to not wait for it.
+    pub async fn connect<F>(
+        url: Url,
+        mode: ConnectionMode,
+        opts: ConnectionOptions,
+        retry: RetryConfig,
+        resubscribe: F,
+    ) -> Result<Self, Error>
+    where
+        F: Fn() -> Vec<WsMessage> + Send + Sync + 'static,
+    {
+        let mut ws: Self = Self::new(url, mode, opts, retry, resubscribe);
+        ws.await_connected().await?;
+        Ok(ws)
+    }
+
+    /// Create the WebSocket, without waiting for the first connection
+    ///
+    /// The state is `Connecting`: the first connection is established by [`ReconnectingWebSocket::await_connected`]
+    /// or while polling the stream (without an [`Event::Reconnected`] for it). Sending fails until then.
+    pub fn new<F>(
+        url: Url,
+        mode: ConnectionMode,
+        opts: ConnectionOptions,
+        retry: RetryConfig,
+        resubscribe: F,
+    ) -> Self
+    where
+        F: Fn() -> Vec<WsMessage> + Send + Sync + 'static,
+    {
+        let resubscribe: Resubscribe = Arc::new(resubscribe);
+        let reconnecting: ConnectFuture = Box::pin(connect_and_subscribe(
+            url.clone(),
+            mode.clone(),
+            opts.clone(),
+            retry,
+            resubscribe.clone(),
+        ));
+
+        Self {
+            url,
+            mode,
+            opts,
+            retry,
+            resubscribe,
+            sink: None,
+            stream: None,
+            reconnecting: Some(reconnecting),
+            state: StateNotifier::new(ConnectionState::Connecting),
+            connected_once: false,
+            closed: false,
+        }
+    }
+
+    /// Wait for the first connection to be established
+    ///
+    /// Returns the fatal error if the first connection failed (after the retry attempts): the stream is then ended.
+    /// Once connected, it returns immediately: the reconnections are reported by the stream.
+    /// Returns an error if closed before connecting.
+    pub async fn await_connected(&mut self) -> Result<(), Error> {
+        if self.connected_once {
+            return Ok(());
+        }
+
+        if self.closed {
+            return Err(not_connected());
+        }
+
+        future::poll_fn(|cx| self.poll_connect(cx)).await
+    }
+
+    /// Check if the connection is currently established
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Get the current connection state
+    #[inline]
+    pub fn state(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    /// Subscribe to the connection state transitions
+    ///
+    /// A failed reconnection attempt (that will be retried) doesn't produce a transition.
+    #[inline]
+    pub fn state_events(&self) -> broadcast::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Send a message
+    ///
+    /// Returns an error if the connection is being re-established.
+    pub async fn send(&mut self, msg: WsMessage) -> Result<(), Error> {
+        match &mut self.sink {
+            Some(sink) => sink.send(msg).await,
+            None => Err(not_connected()),
+        }
+    }
+
+    /// Close the connection, permanently
+    pub async fn close(&mut self) -> Result<(), Error> {
+        self.closed = true;
+        self.reconnecting = None;
+        self.stream = None;
+        self.state.disconnected(DisconnectReason::Closed);
+
+        match self.sink.take() {
+            Some(mut sink) => sink.close().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Drive the connection in progress (if any)
+    fn poll_connect(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let Some(fut) = &mut self.reconnecting else {
+            return Poll::Ready(Ok(()));
+        };
+
+        let res: Result<(Sink, Stream), Error> = ready!(fut.as_mut().poll(cx));
+        self.reconnecting = None;
+
+        match res {
+            Ok((sink, stream)) => {
+                self.sink = Some(sink);
+                self.stream = Some(stream);
+                self.connected_once = true;
+                self.state.set(ConnectionState::Connected);
+                Poll::Ready(Ok(()))
+            }
+            Err(e) => {
+                self.closed = true;
+                self.state
+                    .set(ConnectionState::Disconnected(DisconnectReason::Error(
+                        e.to_string(),
+                    )));
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+
+    fn reconnect(&mut self, reason: DisconnectReason) {
+        self.sink = None;
+        self.stream = None;
+        self.state.disconnected(reason);
+        self.state.set(ConnectionState::Connecting);
+        self.reconnecting = Some(Box::pin(connect_and_subscribe(
+            self.url.clone(),
+            self.mode.clone(),
+            self.opts.clone(),
+            self.retry,
+            self.resubscribe.clone(),
+        )));
+    }
+}
+
+impl StreamTrait for ReconnectingWebSocket {
+    type Item = Result<Event, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.closed {
+                return Poll::Ready(None);
+            }
+
+            if self.reconnecting.is_some() {
+                let first: bool = !self.connected_once;
+
+                match ready!(self.poll_connect(cx)) {
+                    // The first connection is not a reconnection
+                    Ok(()) if first => continue,
+                    Ok(()) => return Poll::Ready(Some(Ok(Event::Reconnected))),
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                }
+            }
+
+            let Some(stream) = &mut self.stream else {
+                return Poll::Ready(None);
+            };
+
+            match stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(msg))) => return Poll::Ready(Some(Ok(Event::Message(msg)))),
+                Poll::Ready(Some(Err(e))) => {
+                    if !e.is_retryable() {
+                        self.closed = true;
+                        self.state
+                            .disconnected(DisconnectReason::Error(e.to_string()));
+                        return Poll::Ready(Some(Err(e)));
+                    }
+
+                    tracing::debug!(error = %e, "Connection to {} failed, reconnecting.", self.url);
+                    self.reconnect(DisconnectReason::Error(e.to_string()));
+                }
+                Poll::Ready(None) => {
+                    tracing::debug!("Connection to {} closed, reconnecting.", self.url);
+                    self.reconnect(DisconnectReason::Closed);
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+async fn connect_and_subscribe(
+    url: Url,
+    mode: ConnectionMode,
+    opts: ConnectionOptions,
+    retry: RetryConfig,
+    resubscribe: Resubscribe,
+) -> Result<(Sink, Stream), Error> {
+    let (mut sink, stream) = crate::connect_with_retry(&url, &mode, &opts, &retry).await?;
+
+    for msg in resubscribe() {
+        sink.send(msg).await?;
+    }
+
+    Ok((sink, stream))
+}
+
+#[inline]
+fn not_connected() -> Error {
+    #[cfg(not(target_arch = "wasm32"))]
+    return Error::Ws(Box::new(WsError::AlreadyClosed));
+
+    #[cfg(target_arch = "wasm32")]
+    return Error::ConnectionNotOpen;
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::EchoServer;
+
+    fn url(addr: SocketAddr) -> Url {
+        Url::parse(&format!("ws://{addr}")).unwrap()
+    }
+
+    fn retry() -> RetryConfig {
+        RetryConfig {
+            initial_delay: Duration::from_millis(10),
+            jitter: false,
+            ..Default::default()
+        }
+    }
+
+    fn text(text: &str) -> WsMessage {
+        WsMessage::Text(text.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        // Dropped after echoing the resubscribe message and one more
+        let (addr, _server) = EchoServer::new().drop_after(2).spawn().await;
+        let subscribed = Arc::new(AtomicUsize::new(0));
+        let counter = subscribed.clone();
+
+        let mut ws = ReconnectingWebSocket::connect(
+            url(addr),
+            ConnectionMode::Direct,
+            ConnectionOptions::new(),
+            retry(),
+            move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                vec![text("subscribe")]
+            },
+        )
+        .await
+        .unwrap();
+
+        for reconnected in [false, true] {
+            if reconnected {
+                assert_eq!(ws.next().await.unwrap().unwrap(), Event::Reconnected);
+                assert!(ws.is_connected());
+            }
+
+            // Sent on each connection, before the other messages
+            assert_eq!(
+                ws.next().await.unwrap().unwrap(),
+                Event::Message(text("subscribe"))
+            );
+
+            ws.send(text("hello")).await.unwrap();
+            assert_eq!(
+                ws.next().await.unwrap().unwrap(),
+                Event::Message(text("hello"))
+            );
+        }
+
+        assert_eq!(subscribed.load(Ordering::SeqCst), 2);
+        ws.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_await_connected() {
+        let (addr, _server) = EchoServer::new().spawn().await;
+
+        let mut ws = ReconnectingWebSocket::new(
+            url(addr),
+            ConnectionMode::Direct,
+            ConnectionOptions::new(),
+            retry(),
+            Vec::new,
+        );
+        assert_eq!(ws.state(), ConnectionState::Connecting);
+        assert!(ws.send(text("early")).await.is_err());
+
+        ws.await_connected().await.unwrap();
+        assert!(ws.is_connected());
+        assert_eq!(ws.state(), ConnectionState::Connected);
+
+        // Already connected
+        ws.await_connected().await.unwrap();
+
+        ws.send(text("hello")).await.unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Event::Message(text("hello"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_await_connected_fatal_error() {
+        let url: Url = Url::parse("http://127.0.0.1:1").unwrap();
+
+        let mut ws = ReconnectingWebSocket::new(
+            url,
+            ConnectionMode::Direct,
+            ConnectionOptions::new(),
+            retry(),
+            Vec::new,
+        );
+
+        match ws.await_connected().await {
+            Err(e) => assert!(!e.is_retryable()),
+            Ok(()) => panic!("connection succeeded"),
+        }
+        assert!(!ws.is_connected());
+        assert!(matches!(ws.state(), ConnectionState::Disconnected(..)));
+
+        // Ended
+        assert!(ws.next().await.is_none());
+        assert!(ws.await_connected().await.is_err());
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Retry
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Retry config
+///
+/// The delay between attempts starts from `initial_delay` and is multiplied by `multiplier`
+/// after each failure, without exceeding `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryConfig {
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    /// Max delay between two attempts
+    pub max_delay: Duration,
+    /// Multiplier applied to the delay after each failure
+    pub multiplier: f64,
+    /// Max number of attempts (including the first one)
+    pub max_attempts: u32,
+    /// Randomize each delay between 50% and 100% of its value
+    pub jitter: bool,
+    /// Max total time spent connecting, including the attempts and the delays between them
+    ///
+    /// When exceeded, even in the middle of an attempt or of a delay, a timeout error is returned.
+    /// `None` means no limit, other than `max_attempts`.
+    pub max_total_connect_duration: Option<Duration>,
+    /// Max delay requested by the server with the `Retry-After` header of a rejected handshake
+    ///
+    /// The requested delay (capped to this value) replaces the backoff one. Ignored on WASM: the browser
+    /// doesn't expose the handshake response.
+    pub max_retry_after: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            max_attempts: 5,
+            jitter: true,
+            max_total_connect_duration: None,
+            max_retry_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Get the delay to wait after the `failures`-th failed attempt
+    pub(crate) fn delay(&self, failures: u32) -> Duration {
+        let exp: i32 = i32::try_from(failures.saturating_sub(1)).unwrap_or(i32::MAX);
+        let mut delay: f64 = self.initial_delay.as_secs_f64() * self.multiplier.powi(exp);
+
+        // Bound the delay (`min` also takes care of `NaN` values)
+        delay = delay.min(self.max_delay.as_secs_f64()).max(0.0);
+
+        if self.jitter {
+            delay *= 0.5 + random() / 2.0;
+        }
+
+        // `Duration::MAX` isn't exactly representable as `f64`: rounded up, it overflows
+        Duration::try_from_secs_f64(delay).unwrap_or(self.max_delay)
+    }
+
+    /// Get the delay to wait after the `failures`-th failed attempt, following the `Retry-After` delay, if any
+    pub(crate) fn delay_after(&self, failures: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(delay) => delay.min(self.max_retry_after),
+            None => self.delay(failures),
+        }
+    }
+}
+
+/// Get a pseudo-random number in the `[0, 1)` range
+fn random() -> f64 {
+    let hasher = RandomState::new().build_hasher();
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[inline]
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(not(target_arch = "wasm32"))]
+    crate::native::runtime::sleep(duration).await;
+
+    #[cfg(target_arch = "wasm32")]
+    async_utility::time::sleep(duration).await;
+}
+
+/// Run the future within the timeout (if any), returning `None` on expiry
+#[inline]
+pub(crate) async fn timeout<F>(duration: Option<Duration>, future: F) -> Option<F::Output>
+where
+    F: Future,
+{
+    #[cfg(not(target_arch = "wasm32"))]
+    match duration {
+        Some(duration) => crate::native::runtime::timeout(duration, future).await.ok(),
+        None => Some(future.await),
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async_utility::time::timeout(duration, future).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_backoff() {
+        let retry = RetryConfig {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: false,
+            ..Default::default()
+        };
+
+        assert_eq!(retry.delay(1), Duration::from_millis(100));
+        assert_eq!(retry.delay(2), Duration::from_millis(200));
+        assert_eq!(retry.delay(3), Duration::from_millis(400));
+        assert_eq!(retry.delay(5), Duration::from_secs(1));
+        assert_eq!(retry.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_delay_max_duration() {
+        let retry = RetryConfig {
+            max_delay: Duration::MAX,
+            jitter: false,
+            ..Default::default()
+        };
+        assert_eq!(retry.delay(u32::MAX), Duration::MAX);
+
+        let retry = RetryConfig {
+            jitter: true,
+            ..retry
+        };
+        assert!(retry.delay(u32::MAX) >= Duration::MAX / 2);
+    }
+
+    #[test]
+    fn test_delay_nan_multiplier() {
+        let retry = RetryConfig {
+            multiplier: f64::NAN,
+            jitter: false,
+            ..Default::default()
+        };
+        assert_eq!(retry.delay(2), retry.max_delay);
+    }
+}
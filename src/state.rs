@@ -0,0 +1,111 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection state
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+/// Buffer size of the state events channel
+const EVENTS_CHANNEL_SIZE: usize = 16;
+
+/// Connection state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connecting (or reconnecting)
+    Connecting,
+    /// Connected
+    Connected,
+    /// Disconnected
+    Disconnected(DisconnectReason),
+}
+
+impl ConnectionState {
+    /// Check if is [`ConnectionState::Connected`]
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Self::Connected)
+    }
+}
+
+/// Why the connection was lost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Closed by us or by the server
+    Closed,
+    /// Failed (i.e. network error, keepalive timeout)
+    Error(String),
+}
+
+/// Keep track of the connection state, notifying the transitions
+#[derive(Debug)]
+pub(crate) struct StateNotifier {
+    state: Mutex<ConnectionState>,
+    /// Mirror of `state.is_connected()`, to be checked without locking
+    connected: AtomicBool,
+    events: broadcast::Sender<ConnectionState>,
+}
+
+impl StateNotifier {
+    pub(crate) fn new(state: ConnectionState) -> Self {
+        let (events, ..) = broadcast::channel(EVENTS_CHANNEL_SIZE);
+        Self {
+            connected: AtomicBool::new(state.is_connected()),
+            state: Mutex::new(state),
+            events,
+        }
+    }
+
+    pub(crate) fn get(&self) -> ConnectionState {
+        match self.state.lock() {
+            Ok(state) => state.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<ConnectionState> {
+        self.events.subscribe()
+    }
+
+    #[inline]
+    pub(crate) fn set(&self, new: ConnectionState) {
+        self.update(|_| Some(new));
+    }
+
+    /// Mark as disconnected, keeping the first reason
+    #[inline]
+    pub(crate) fn disconnected(&self, reason: DisconnectReason) {
+        self.update(|state| match state {
+            ConnectionState::Disconnected(..) => None,
+            _ => Some(ConnectionState::Disconnected(reason)),
+        });
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&ConnectionState) -> Option<ConnectionState>,
+    {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => e.into_inner(),
+        };
+
+        if let Some(new) = f(&state) {
+            if *state != new {
+                self.connected.store(new.is_connected(), Ordering::Release);
+                *state = new.clone();
+
+                // No receivers isn't an error
+                let _ = self.events.send(new);
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection stats
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Snapshot of the traffic of a connection
+///
+/// Counts the messages passing through the [`Sink`](crate::Sink) and the [`Stream`](crate::Stream)
+/// (control frames included) and their payload bytes. The frames sent automatically
+/// (i.e. keepalive pings, replies to the pings) are not counted.
+///
+/// Compression is not supported, so the payload bytes are also the bytes on the wire (framing excluded).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionStats {
+    /// Payload bytes sent
+    pub bytes_sent: u64,
+    /// Payload bytes received
+    pub bytes_received: u64,
+    /// Messages sent
+    pub messages_sent: u64,
+    /// Messages received
+    pub messages_received: u64,
+    /// Outgoing messages dropped because the send buffer was full (see [`OverflowPolicy`](crate::OverflowPolicy))
+    pub messages_dropped: u64,
+    /// Keepalive pings currently waiting for the pong (always `0` on WASM)
+    pub unanswered_pings: u64,
+}
+
+/// Traffic counters, shared by the connection halves
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounter {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    messages_dropped: AtomicU64,
+    unanswered_pings: AtomicU64,
+}
+
+impl StatsCounter {
+    #[inline]
+    pub(crate) fn sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message not queued because the send buffer was full
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A queued message removed from the send buffer, so not sent anymore
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn unqueued(&self, len: usize) {
+        self.bytes_sent.fetch_sub(len as u64, Ordering::Relaxed);
+        self.messages_sent.fetch_sub(1, Ordering::Relaxed);
+        self.dropped();
+    }
+
+    /// Keepalive pings waiting for the pong
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn set_unanswered_pings(&self, pings: usize) {
+        self.unanswered_pings.store(pings as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            messages_dropped: self.messages_dropped.load(Ordering::Relaxed),
+            unanswered_pings: self.unanswered_pings.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Test utils
+//!
+//! Local echo server, to test the clients without a remote server.
+//!
+//! The server runs on tokio, whatever the runtime of the clients.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time;
+
+use crate::WsMessage;
+
+/// Echo server, answering the text and binary messages with the same message
+///
+/// The pings are answered with a pong and the close frames with a close frame.
+#[derive(Debug, Clone, Default)]
+pub struct EchoServer {
+    drop_after: Option<usize>,
+    delay: Option<Duration>,
+}
+
+impl EchoServer {
+    /// New echo server
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop the connection (without a close frame) after echoing `messages` messages (default: never)
+    #[inline]
+    pub fn drop_after(mut self, messages: usize) -> Self {
+        self.drop_after = Some(messages);
+        self
+    }
+
+    /// Wait `delay` before echoing each message (default: no delay)
+    #[inline]
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Listen on a random port of `127.0.0.1`
+    ///
+    /// Aborting the returned handle stops the server and drops all the connections.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the listener can't be bound.
+    pub async fn spawn(self) -> (SocketAddr, JoinHandle<()>) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind the echo server");
+        let addr: SocketAddr = listener
+            .local_addr()
+            .expect("failed to get the echo server address");
+
+        let handle = tokio::spawn(async move {
+            // Dropped with the server task, aborting the connections
+            let mut connections: JoinSet<()> = JoinSet::new();
+
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.spawn(self.clone().serve(stream));
+
+                // Reap the closed connections
+                while connections.try_join_next().is_some() {}
+            }
+        });
+
+        (addr, handle)
+    }
+
+    async fn serve(self, stream: TcpStream) {
+        let mut ws = match tokio_tungstenite::accept_async(stream).await {
+            Ok(ws) => ws,
+            Err(e) => {
+                tracing::debug!(error = %e, "Echo server handshake failed.");
+                return;
+            }
+        };
+
+        let mut echoed: usize = 0;
+
+        while !self.is_done(echoed) {
+            let msg: WsMessage = match ws.next().await {
+                Some(Ok(msg)) => msg,
+                _ => return,
+            };
+
+            if !matches!(msg, WsMessage::Text(..) | WsMessage::Binary(..)) {
+                // Pings and close frames are answered by `tungstenite`
+                continue;
+            }
+
+            if let Some(delay) = self.delay {
+                time::sleep(delay).await;
+            }
+
+            if ws.send(msg).await.is_err() {
+                return;
+            }
+
+            echoed += 1;
+        }
+
+        tracing::debug!("Echo server dropping the connection after {echoed} messages.");
+    }
+
+    #[inline]
+    fn is_done(&self, echoed: usize) -> bool {
+        self.drop_after.is_some_and(|max| echoed >= max)
+    }
+}
+
+/// Spawn an [`EchoServer`] with the default settings
+///
+/// Returns the address of the server and the handle of its task.
+#[inline]
+pub async fn spawn_echo_server() -> (SocketAddr, JoinHandle<()>) {
+    EchoServer::new().spawn().await
+}
+
+/// Raw WebSocket server, sending the frames `tungstenite` refuses to send (i.e. invalid ones)
+#[cfg(test)]
+pub(crate) mod raw {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::oneshot;
+    use tokio::time;
+    use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+    use url::Url;
+
+    /// Accept one connection, write `frames` after the handshake and read until the client drops it
+    ///
+    /// The receiver gets the bytes written by the client after the handshake.
+    pub(crate) async fn server(frames: Vec<u8>) -> (Url, oneshot::Receiver<Vec<u8>>) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: Url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let (closed_tx, closed_rx) = oneshot::channel();
+
+        tokio::spawn(async move {
+            let mut stream: TcpStream = accept(&listener).await;
+            stream.write_all(&frames).await.unwrap();
+
+            let mut received: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 1024];
+            while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                received.extend_from_slice(&buf[..n]);
+            }
+            let _ = closed_tx.send(received);
+        });
+
+        (url, closed_rx)
+    }
+
+    /// Wait for the client to drop the connection (up to 5 secs), returning the bytes written after the handshake
+    pub(crate) async fn wait_closed(closed: oneshot::Receiver<Vec<u8>>) -> Vec<u8> {
+        time::timeout(Duration::from_secs(5), closed)
+            .await
+            .expect("connection not closed")
+            .unwrap()
+    }
+
+    /// Read the HTTP request (until the empty line), or until the client drops the connection
+    pub(crate) async fn read_request(stream: &mut TcpStream) -> Vec<u8> {
+        let mut request: Vec<u8> = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(..) => break,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        request
+    }
+
+    /// Accept a connection and answer the WebSocket handshake
+    pub(crate) async fn accept(listener: &TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let request: String = String::from_utf8(read_request(&mut stream).await).unwrap();
+        let key: &str = request
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("sec-websocket-key")
+                    .then_some(value.trim())
+            })
+            .unwrap();
+        let response: String = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            derive_accept_key(key.as_bytes())
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+
+        stream
+    }
+
+    /// Unmasked final frame
+    pub(crate) fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame: Vec<u8> = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Code of the first close frame written by the client (masked)
+    pub(crate) fn close_code(mut bytes: &[u8]) -> Option<u16> {
+        while bytes.len() >= 6 {
+            let opcode: u8 = bytes[0] & 0x0f;
+            let (len, offset): (usize, usize) = match bytes[1] & 0x7f {
+                126 => (u16::from_be_bytes([bytes[2], bytes[3]]) as usize, 4),
+                127 => return None,
+                len => (len as usize, 2),
+            };
+            let mask: &[u8] = bytes.get(offset..offset + 4)?;
+            let payload: &[u8] = bytes.get(offset + 4..offset + 4 + len)?;
+
+            if opcode == 0x8 {
+                let code: Vec<u8> = payload
+                    .iter()
+                    .take(2)
+                    .zip(mask)
+                    .map(|(b, m)| b ^ m)
+                    .collect();
+                return Some(u16::from_be_bytes(code.try_into().ok()?));
+            }
+
+            bytes = &bytes[offset + 4 + len..];
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use url::Url;
+
+    use super::*;
+    use crate::{ConnectionMode, ConnectionOptions, Error};
+
+    fn url(addr: SocketAddr) -> Url {
+        Url::parse(&format!("ws://{addr}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let (addr, _server) = spawn_echo_server().await;
+        let (mut tx, mut rx) = crate::connect(
+            &url(addr),
+            &ConnectionMode::Direct,
+            ConnectionOptions::new(),
+        )
+        .await
+        .unwrap();
+
+        tx.send(WsMessage::Text(String::from("hello")))
+            .await
+            .unwrap();
+        tx.send(WsMessage::Binary(vec![1, 2, 3])).await.unwrap();
+
+        assert_eq!(
+            rx.next().await.unwrap().unwrap(),
+            WsMessage::Text(String::from("hello"))
+        );
+        assert_eq!(
+            rx.next().await.unwrap().unwrap(),
+            WsMessage::Binary(vec![1, 2, 3])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drop_after() {
+        let (addr, _server) = EchoServer::new().drop_after(1).spawn().await;
+        let (mut tx, mut rx) = crate::connect(
+            &url(addr),
+            &ConnectionMode::Direct,
+            ConnectionOptions::new(),
+        )
+        .await
+        .unwrap();
+
+        tx.send(WsMessage::Text(String::from("first")))
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await.unwrap().unwrap(),
+            WsMessage::Text(String::from("first"))
+        );
+
+        // Dropped without a close frame
+        let _ = tx.send(WsMessage::Text(String::from("second"))).await;
+        assert!(!matches!(rx.next().await, Some(Ok(..))));
+    }
+
+    #[tokio::test]
+    async fn test_delay() {
+        let (addr, _server) = EchoServer::new()
+            .delay(Duration::from_millis(300))
+            .spawn()
+            .await;
+        let (mut tx, mut rx) = crate::connect(
+            &url(addr),
+            &ConnectionMode::Direct,
+            ConnectionOptions::new(),
+        )
+        .await
+        .unwrap();
+
+        tx.send(WsMessage::Text(String::from("hello")))
+            .await
+            .unwrap();
+
+        let res = rx.recv_timeout(Duration::from_millis(50)).await;
+        assert!(matches!(res, Err(Error::Timeout)));
+
+        // Not lost by the timeout
+        let msg = rx.recv_timeout(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(msg, Some(WsMessage::Text(String::from("hello"))));
+    }
+}
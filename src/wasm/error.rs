@@ -5,6 +5,8 @@
 use core::fmt;
 use core::str::Utf8Error;
 
+use url::Url;
+
 use crate::wasm::CloseEvent;
 
 /// WebSocket Error
@@ -45,11 +47,10 @@ pub enum Error {
     InvalidEncoding,
     /// When converting the JavaScript Message into a WsMessage, it's not possible to
     /// convert Blob type messages, as Blob is a streaming type, that needs to be read
-    /// asynchronously. If you are using the type without setting up the connection with
-    /// [`WsMeta::connect`](crate::WsMeta::connect), you have to make sure to set the binary
-    /// type of the connection to `ArrayBuffer`.
+    /// asynchronously.
     ///
-    /// Happens in `impl TryFrom< MessageEvent > for WsMessage`.
+    /// Happens in `impl TryFrom< MessageEvent > for WsMessage`, or if the asynchronous read
+    /// fails (binary type [`Blob`](crate::wasm::BinaryType::Blob)).
     CantDecodeBlob,
     /// When converting the JavaScript Message into a WsMessage, the data type was neither
     /// `Arraybuffer`, `String` nor `Blob`. This should never happen. If it does, please
@@ -60,9 +61,29 @@ pub enum Error {
     Dom(u16),
     Other(String),
     Timeout,
+    /// Custom HTTP headers are not supported by the browser WebSocket API
+    HeadersNotSupported,
+    /// Binding the local address is not supported by the browser WebSocket API
+    BindNotSupported,
+    /// Connection mode not supported by the browser WebSocket API
+    UnsupportedMode,
+    /// Connection cancelled
+    Cancelled,
+    /// All the connections of `connect_any` failed (empty if no URL was given)
+    AllConnectionsFailed(Vec<(Url, Error)>),
+    /// Message not deserializable from JSON (error message of `serde_json`)
+    #[cfg(feature = "serde")]
+    Json(String),
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Utf8(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -98,6 +119,37 @@ impl fmt::Display for Error {
             Self::Dom(code) => write!(f, "DOM Exception: {code}"),
             Self::Other(e) => write!(f, "{e}"),
             Self::Timeout => write!(f, "timeout"),
+            Self::HeadersNotSupported => write!(f, "custom HTTP headers are not supported"),
+            Self::BindNotSupported => write!(f, "local address binding not supported"),
+            Self::UnsupportedMode => write!(f, "connection mode not supported"),
+            Self::Cancelled => write!(f, "connection cancelled"),
+            Self::AllConnectionsFailed(errors) => {
+                if errors.is_empty() {
+                    return write!(f, "no URL to connect to");
+                }
+
+                write!(f, "all the {} connections failed", errors.len())?;
+                for (url, e) in errors {
+                    write!(f, "; {url}: {e}")?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "serde")]
+            Self::Json(e) => write!(f, "invalid JSON message: {e}"),
+        }
+    }
+}
+
+impl Error {
+    /// Check if the error is transient, so the connection may be retried
+    #[inline]
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::AllConnectionsFailed(errors) => errors.iter().any(|(_, e)| e.is_retryable()),
+            e => matches!(
+                e,
+                Self::ConnectionFailed { .. } | Self::ConnectionNotOpen | Self::Timeout
+            ),
         }
     }
 }
@@ -107,3 +159,10 @@ impl From<Utf8Error> for Error {
         Self::Utf8(e)
     }
 }
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e.to_string())
+    }
+}
@@ -6,10 +6,32 @@ use core::{fmt, str};
 
 use js_sys::{ArrayBuffer, Uint8Array};
 use wasm_bindgen::JsCast;
-use web_sys::{Blob, MessageEvent};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{BinaryType as JsBinaryType, Blob, MessageEvent};
 
 use crate::wasm::Error;
 
+/// How the browser delivers the binary messages
+///
+/// See [MDN Documentation](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/binaryType).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum BinaryType {
+    /// `ArrayBuffer`: the message is converted synchronously, as soon as it arrives
+    #[default]
+    ArrayBuffer,
+    /// `Blob`: the message is read asynchronously (the order of the messages is preserved)
+    Blob,
+}
+
+impl From<BinaryType> for JsBinaryType {
+    fn from(binary_type: BinaryType) -> Self {
+        match binary_type {
+            BinaryType::ArrayBuffer => Self::Arraybuffer,
+            BinaryType::Blob => Self::Blob,
+        }
+    }
+}
+
 /// Represents a WebSocket Message, after converting from JavaScript type.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum WsMessage {
@@ -56,7 +78,7 @@ impl WsMessage {
 
 /// This will convert the JavaScript event into a WsMessage. Note that this
 /// will only work if the connection is set to use the binary type ArrayBuffer.
-/// On binary type Blob, this will panic.
+/// On binary type Blob, this will return [`Error::CantDecodeBlob`]: use [`read_blob`] instead.
 impl TryFrom<MessageEvent> for WsMessage {
     type Error = Error;
 
@@ -78,8 +100,7 @@ impl TryFrom<MessageEvent> for WsMessage {
                 None => Err(Error::InvalidEncoding),
             },
 
-            // We have set the binary mode to array buffer (WsMeta::connect), so normally this shouldn't happen.
-            // That is as long as this is used within the context of the WsMeta constructor.
+            // The Blob's must be read asynchronously (see `read_blob`)
             d if d.is_instance_of::<Blob>() => Err(Error::CantDecodeBlob),
 
             // should never happen.
@@ -88,6 +109,14 @@ impl TryFrom<MessageEvent> for WsMessage {
     }
 }
 
+/// Read a Blob message (binary type Blob)
+pub(crate) async fn read_blob(blob: Blob) -> Result<WsMessage, Error> {
+    let buf = JsFuture::from(blob.array_buffer())
+        .await
+        .map_err(|_| Error::CantDecodeBlob)?;
+    Ok(WsMessage::Binary(Uint8Array::new(&buf).to_vec()))
+}
+
 impl From<Vec<u8>> for WsMessage {
     fn from(vec: Vec<u8>) -> Self {
         WsMessage::Binary(vec)
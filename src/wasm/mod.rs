@@ -6,11 +6,8 @@
 
 #![allow(clippy::arc_with_non_send_sync)]
 
-use std::time::Duration;
-
 use async_utility::{task, time};
-use futures_util::stream::{SplitSink, SplitStream};
-use futures_util::StreamExt;
+use tracing::Instrument;
 use url::Url;
 
 mod error;
@@ -18,25 +15,59 @@ mod event;
 mod message;
 mod pharos;
 mod socket;
+mod split;
 mod state;
 mod stream;
 
 pub use self::error::Error;
-use self::event::{CloseEvent, WsEvent};
-pub use self::message::WsMessage;
+pub use self::event::CloseEvent;
+use self::event::WsEvent;
+pub use self::message::{BinaryType, WsMessage};
 use self::pharos::SharedPharos;
 use self::socket::WebSocket;
+pub use self::split::{Sink, Stream};
 use self::state::WsState;
 use self::stream::WsStream;
+use crate::{id, ConnectionMode, ConnectionOptions};
+
+pub async fn connect(
+    url: &Url,
+    mode: &ConnectionMode,
+    opts: &ConnectionOptions,
+) -> Result<(Sink, Stream), Error> {
+    // The browser manages the connection
+    if !matches!(mode, ConnectionMode::Direct) {
+        return Err(Error::UnsupportedMode);
+    }
+
+    // The browser WebSocket API doesn't allow custom headers
+    if !opts.headers.is_empty() {
+        return Err(Error::HeadersNotSupported);
+    }
+
+    // The browser manages the sockets
+    if opts.is_bound() {
+        return Err(Error::BindNotSupported);
+    }
+
+    let id: u64 = id::next();
+    let span = tracing::debug_span!("connect", id, %url);
+
+    tracing::debug!(parent: &span, "Connecting.");
+
+    let (ws, stream) = time::timeout(
+        Some(opts.timeout),
+        WebSocket::connect(url, &opts.subprotocols, opts.binary_type),
+    )
+    .instrument(span.clone())
+    .await
+    .unwrap_or(Err(Error::Timeout))
+    .inspect_err(|e| tracing::debug!(parent: &span, error = %e, "Connection failed."))?;
 
-pub type Sink = SplitSink<WsStream, WsMessage>;
-pub type Stream = SplitStream<WsStream>;
+    tracing::debug!(parent: &span, "Connected.");
 
-pub async fn connect(url: &Url, timeout: Duration) -> Result<(Sink, Stream), Error> {
-    let (_ws, stream) = time::timeout(Some(timeout), WebSocket::connect(url))
-        .await
-        .ok_or(Error::Timeout)??;
-    Ok(stream.split())
+    // The browser closes the connection if the server selects a subprotocol not requested
+    Ok(split::split(id, stream, ws, opts.close_timeout).await)
 }
 
 /// Helper function to reduce code bloat
@@ -6,13 +6,16 @@ use std::fmt;
 use std::sync::Arc;
 
 use futures::StreamExt;
+use js_sys::Array;
 use url::Url;
 use wasm_bindgen::closure::Closure;
-use wasm_bindgen::JsCast;
-use web_sys::{BinaryType, CloseEvent as JsCloseEvt, DomException, WebSocket as WebSysSocket};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{CloseEvent as JsCloseEvt, DomException, WebSocket as WebSysSocket};
 
-use crate::wasm::pharos::{Filter, Observable, Observe, ObserveConfig, PharErr, SharedPharos};
-use crate::wasm::{notify, CloseEvent, Error, WsEvent, WsState, WsStream};
+use crate::wasm::pharos::{
+    Events, Filter, Observable, Observe, ObserveConfig, PharErr, SharedPharos,
+};
+use crate::wasm::{notify, BinaryType, CloseEvent, Error, WsEvent, WsState, WsStream};
 
 /// The metadata related to a websocket. Allows access to the methods on the WebSocket API.
 /// This is split from the `Stream`/`Sink` so you can pass the latter to a combinator whilst
@@ -33,8 +36,27 @@ impl WebSocket {
 
     /// Connect to the server. The future will resolve when the connection has been established with a successful WebSocket
     /// handshake.
-    pub async fn connect(url: &Url) -> Result<(Self, WsStream), Error> {
-        let ws: Arc<WebSysSocket> = match WebSysSocket::new(url.as_str()) {
+    ///
+    /// If `protocols` is not empty, the subprotocols are requested in the handshake (the empty names are skipped).
+    pub async fn connect(
+        url: &Url,
+        protocols: &[String],
+        binary_type: BinaryType,
+    ) -> Result<(Self, WsStream), Error> {
+        let protocols: Vec<&str> = protocols
+            .iter()
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .collect();
+
+        let ws = if protocols.is_empty() {
+            WebSysSocket::new(url.as_str())
+        } else {
+            let protocols: Array = protocols.iter().map(|p| JsValue::from_str(p)).collect();
+            WebSysSocket::new_with_str_sequence(url.as_str(), &protocols)
+        };
+
+        let ws: Arc<WebSysSocket> = match ws {
             Ok(ws) => Arc::new(ws),
             Err(e) => {
                 let de: &DomException = e.unchecked_ref();
@@ -138,8 +160,8 @@ impl WebSocket {
         // so we let it take over the responsibility of unregistering the callbacks by disabling our guard.
         std::mem::forget(guard);
 
-        // We don't handle Blob's
-        ws.set_binary_type(BinaryType::Arraybuffer);
+        // The Blob's are read asynchronously by the stream
+        ws.set_binary_type(binary_type.into());
 
         Ok((
             Self {
@@ -160,6 +182,57 @@ impl WebSocket {
     pub fn url(&self) -> String {
         self.ws.url()
     }
+
+    /// Verify the [WsState] of the connection.
+    pub fn ready_state(&self) -> Result<WsState, Error> {
+        self.ws.ready_state().try_into()
+    }
+
+    /// Observe the close event of the connection.
+    pub(crate) async fn observe_close(&self) -> Events<WsEvent> {
+        self.pharos
+            .observe_shared(Filter::Pointer(WsEvent::is_closed).into())
+            .await
+            .expect("we didn't close pharos")
+    }
+
+    /// Close the socket with a code and a reason. The future will resolve when the socket is closed.
+    ///
+    /// The code must be `1000` or in the `3000..=4999` range and the reason can't be longer than 123 bytes.
+    pub async fn close_with(&self, code: u16, reason: &str) -> Result<(), Error> {
+        if reason.len() > 123 {
+            return Err(Error::ReasonStringToLong);
+        }
+
+        // Observe before closing, to not miss the event
+        let mut evts = self.observe_close().await;
+
+        match self.ready_state()? {
+            WsState::Closed => return Ok(()),
+            WsState::Closing => {}
+            _ => {
+                if self.ws.close_with_code_and_reason(code, reason).is_err() {
+                    return Err(Error::InvalidCloseCode { supplied: code });
+                }
+
+                notify(self.pharos.clone(), WsEvent::Closing);
+            }
+        }
+
+        evts.next().await;
+
+        Ok(())
+    }
+
+    /// Retrieve the subprotocol selected by the server. Empty if none was selected.
+    pub fn protocol(&self) -> String {
+        self.ws.protocol()
+    }
+
+    /// Retrieve the extensions selected by the server. Empty if none was selected.
+    pub fn extensions(&self) -> String {
+        self.ws.extensions()
+    }
 }
 
 impl fmt::Debug for WebSocket {
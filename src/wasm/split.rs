@@ -0,0 +1,400 @@
+// Copyright (c) 2023-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Split halves of a [`WsStream`]
+
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_utility::{task, time};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{Sink as SinkTrait, Stream as StreamTrait, StreamExt};
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
+use tokio::sync::broadcast;
+
+use crate::batch::{self, SendBatchError};
+#[cfg(feature = "serde")]
+use crate::json::Json;
+use crate::payload::{self, Payload};
+use crate::state::StateNotifier;
+use crate::stats::StatsCounter;
+use crate::wasm::{CloseEvent, Error, WebSocket, WsEvent, WsMessage, WsStream};
+use crate::{ConnectionState, ConnectionStats, DisconnectReason};
+
+/// Connection metadata, shared by the [`Sink`] and [`Stream`] halves
+#[derive(Debug)]
+pub(crate) struct Metadata {
+    /// Connection id
+    id: u64,
+    socket: WebSocket,
+    /// Browser WebSocket, for the features not exposed by the crate
+    raw: web_sys::WebSocket,
+    /// Subprotocol negotiated during the handshake
+    protocol: Option<String>,
+    /// Extensions negotiated during the handshake
+    extensions: Option<String>,
+    /// Close event of the connection
+    close_event: RefCell<Option<CloseEvent>>,
+    /// How long to wait for the close event
+    close_timeout: Duration,
+    /// Connection state
+    state: StateNotifier,
+    /// Traffic counters
+    stats: StatsCounter,
+}
+
+pub(crate) async fn split(
+    id: u64,
+    stream: WsStream,
+    socket: WebSocket,
+    close_timeout: Duration,
+) -> (Sink, Stream) {
+    let protocol: String = socket.protocol();
+    let extensions: String = socket.extensions();
+    let mut evts = socket.observe_close().await;
+
+    let metadata: Rc<Metadata> = Rc::new(Metadata {
+        id,
+        socket,
+        raw: stream.wrapped().clone(),
+        protocol: (!protocol.is_empty()).then_some(protocol),
+        extensions: (!extensions.is_empty()).then_some(extensions),
+        close_event: RefCell::new(None),
+        close_timeout,
+        state: StateNotifier::new(ConnectionState::Connected),
+        stats: StatsCounter::default(),
+    });
+
+    // Keep track of the close event. Use a weak reference to not keep the connection alive.
+    let weak = Rc::downgrade(&metadata);
+    task::spawn(async move {
+        if let Some(WsEvent::Closed(evt)) = evts.next().await {
+            if let Some(metadata) = weak.upgrade() {
+                let reason: DisconnectReason = if evt.was_clean {
+                    DisconnectReason::Closed
+                } else {
+                    DisconnectReason::Error(format!(
+                        "closed with code {}: {}",
+                        evt.code, evt.reason
+                    ))
+                };
+                tracing::debug!(id = metadata.id, code = evt.code, "Connection closed.");
+                metadata.state.disconnected(reason);
+                *metadata.close_event.borrow_mut() = Some(evt);
+            }
+        }
+    });
+
+    let (tx, rx) = stream.split();
+    (
+        Sink {
+            inner: tx,
+            metadata: metadata.clone(),
+        },
+        Stream {
+            inner: rx,
+            metadata,
+        },
+    )
+}
+
+/// Kind of the message, for the tracing events
+fn kind(msg: &WsMessage) -> &'static str {
+    match msg {
+        WsMessage::Text(..) => "text",
+        WsMessage::Binary(..) => "binary",
+    }
+}
+
+/// Sending half of a WebSocket connection
+///
+/// Independent of the [`Stream`] half. Closing it closes the connection: the [`Stream`] keeps
+/// returning the queued messages and then ends. Dropping it without closing leaves the [`Stream`] untouched.
+pub struct Sink {
+    inner: SplitSink<WsStream, WsMessage>,
+    metadata: Rc<Metadata>,
+}
+
+impl Sink {
+    /// Get the connection id, unique within the process (the `id` field of the tracing events)
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.metadata.id
+    }
+
+    /// Get a snapshot of the connection traffic
+    #[inline]
+    pub fn stats(&self) -> ConnectionStats {
+        self.metadata.stats.snapshot()
+    }
+
+    /// Check if the connection is still open, without locking
+    ///
+    /// It becomes `false` when the browser reports the close (or an error) of the connection.
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.metadata.state.is_connected()
+    }
+
+    /// Get the current connection state
+    #[inline]
+    pub fn state(&self) -> ConnectionState {
+        self.metadata.state.get()
+    }
+
+    /// Subscribe to the connection state transitions
+    #[inline]
+    pub fn state_events(&self) -> broadcast::Receiver<ConnectionState> {
+        self.metadata.state.subscribe()
+    }
+
+    /// Get the subprotocol negotiated during the handshake
+    #[inline]
+    pub fn protocol(&self) -> Option<&str> {
+        self.metadata.protocol.as_deref()
+    }
+
+    /// Get the extensions negotiated during the handshake (the `Sec-WebSocket-Extensions` header)
+    ///
+    /// The browser WebSocket API doesn't expose the other headers of the handshake response.
+    #[inline]
+    pub fn extensions(&self) -> Option<&str> {
+        self.metadata.extensions.as_deref()
+    }
+
+    /// Get the address of the remote peer
+    ///
+    /// Always `None` on WASM: the browser WebSocket API doesn't expose it.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Get the local address of the connection
+    ///
+    /// Always `None` on WASM: the browser WebSocket API doesn't expose it.
+    #[inline]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Get the browser WebSocket, for the features not exposed by the crate
+    ///
+    /// Don't set its event handlers (`onopen`, `onmessage`, `onerror` and `onclose`):
+    /// they are used by the connection, which would break.
+    #[inline]
+    pub fn web_socket(&self) -> &web_sys::WebSocket {
+        &self.metadata.raw
+    }
+
+    /// Close the connection with a status code and a reason
+    ///
+    /// Wait for the connection to be closed
+    /// (up to 10 secs, see [`ConnectionOptions::close_timeout`](crate::ConnectionOptions::close_timeout)).
+    /// The code must be `1000` or in the `3000..=4999` range and the reason can't be longer than 123 bytes.
+    pub async fn close_with(&mut self, code: u16, reason: Option<String>) -> Result<(), Error> {
+        let reason: String = reason.unwrap_or_default();
+        tracing::debug!(id = self.metadata.id, code, "Closing connection.");
+        time::timeout(
+            Some(self.metadata.close_timeout),
+            self.metadata.socket.close_with(code, &reason),
+        )
+        .await
+        .ok_or(Error::Timeout)?
+    }
+
+    /// Send all the messages, flushing once
+    ///
+    /// Faster than sending them one by one, as the frames are written together.
+    /// Stops on the first error, reporting which message failed.
+    #[inline]
+    pub async fn send_batch<I>(&mut self, msgs: I) -> Result<(), SendBatchError>
+    where
+        I: IntoIterator<Item = WsMessage>,
+    {
+        batch::send_batch(self, msgs).await
+    }
+}
+
+impl SinkTrait<WsMessage> for Sink {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: WsMessage) -> Result<(), Self::Error> {
+        let len: usize = item.len();
+        tracing::trace!(
+            id = self.metadata.id,
+            len,
+            "Sending {} message.",
+            kind(&item)
+        );
+        Pin::new(&mut self.inner).start_send(item)?;
+        self.metadata.stats.sent(len);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Receiving half of a WebSocket connection
+///
+/// Independent of the [`Sink`] half. It ends when the connection is closed.
+/// The browser doesn't deliver the close frame as a message: its code and reason are available
+/// via [`Stream::close_event`] once the stream ended.
+/// The connection is closed when both halves are dropped.
+pub struct Stream {
+    inner: SplitStream<WsStream>,
+    metadata: Rc<Metadata>,
+}
+
+impl Stream {
+    /// Get the connection id, unique within the process (the `id` field of the tracing events)
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.metadata.id
+    }
+
+    /// Get a snapshot of the connection traffic
+    #[inline]
+    pub fn stats(&self) -> ConnectionStats {
+        self.metadata.stats.snapshot()
+    }
+
+    /// Check if the connection is still open, without locking
+    ///
+    /// It becomes `false` when the browser reports the close (or an error) of the connection.
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.metadata.state.is_connected()
+    }
+
+    /// Get the current connection state
+    #[inline]
+    pub fn state(&self) -> ConnectionState {
+        self.metadata.state.get()
+    }
+
+    /// Subscribe to the connection state transitions
+    #[inline]
+    pub fn state_events(&self) -> broadcast::Receiver<ConnectionState> {
+        self.metadata.state.subscribe()
+    }
+
+    /// Get the subprotocol negotiated during the handshake
+    #[inline]
+    pub fn protocol(&self) -> Option<&str> {
+        self.metadata.protocol.as_deref()
+    }
+
+    /// Get the extensions negotiated during the handshake (the `Sec-WebSocket-Extensions` header)
+    ///
+    /// The browser WebSocket API doesn't expose the other headers of the handshake response.
+    #[inline]
+    pub fn extensions(&self) -> Option<&str> {
+        self.metadata.extensions.as_deref()
+    }
+
+    /// Get the address of the remote peer
+    ///
+    /// Always `None` on WASM: the browser WebSocket API doesn't expose it.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Get the local address of the connection
+    ///
+    /// Always `None` on WASM: the browser WebSocket API doesn't expose it.
+    #[inline]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Get the browser WebSocket, for the features not exposed by the crate
+    ///
+    /// Don't set its event handlers (`onopen`, `onmessage`, `onerror` and `onclose`):
+    /// they are used by the connection, which would break.
+    #[inline]
+    pub fn web_socket(&self) -> &web_sys::WebSocket {
+        &self.metadata.raw
+    }
+
+    /// Get the close event of the connection, if closed
+    ///
+    /// Holds the code and the reason sent by the server, if it closed the connection.
+    #[inline]
+    pub fn close_event(&self) -> Option<CloseEvent> {
+        self.metadata.close_event.borrow().clone()
+    }
+
+    /// Receive the next message, waiting up to `timeout`
+    ///
+    /// Returns [`Error::Timeout`] on expiry: the connection is NOT closed and no message is lost,
+    /// so the call can be retried.
+    /// Returns `Ok(None)` if the connection is closed (also while waiting).
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<WsMessage>, Error> {
+        match time::timeout(Some(timeout), self.next()).await {
+            Some(Some(res)) => res.map(Some),
+            Some(None) => Ok(None),
+            None => Err(Error::Timeout),
+        }
+    }
+
+    /// Receive the next data message, with the binary payload as [`Bytes`](crate::Bytes)
+    ///
+    /// The control messages are skipped. See [`Payload`] for the savings over `Vec<u8>`.
+    #[inline]
+    pub async fn next_payload(&mut self) -> Option<Result<Payload, Error>> {
+        payload::next_payload(self).await
+    }
+
+    /// Deserialize the text and binary messages from JSON, skipping the control messages
+    ///
+    /// A malformed message yields an [`Error::Json`] without ending the stream.
+    #[cfg(feature = "serde")]
+    #[inline]
+    pub fn json<T>(self) -> Json<Self, T>
+    where
+        T: DeserializeOwned,
+    {
+        Json::new(self)
+    }
+}
+
+impl StreamTrait for Stream {
+    type Item = Result<WsMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+
+        if let Poll::Ready(Some(Ok(msg))) = &poll {
+            tracing::trace!(
+                id = self.metadata.id,
+                len = msg.len(),
+                "Received {} message.",
+                kind(msg)
+            );
+            self.metadata.stats.received(msg.len());
+        }
+
+        poll
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
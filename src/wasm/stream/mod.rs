@@ -7,6 +7,7 @@ use std::collections::VecDeque;
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
 
@@ -19,9 +20,21 @@ use web_sys::{CloseEvent as JsCloseEvt, WebSocket, *};
 
 pub mod io;
 
+use crate::wasm::message::read_blob;
 use crate::wasm::pharos::{Filter, Observable, SharedPharos};
 use crate::wasm::{notify, Error, WsEvent, WsMessage, WsState};
 
+/// Result of an asynchronous Blob read, `None` until completed
+type BlobSlot = Rc<RefCell<Option<Result<WsMessage, Error>>>>;
+
+/// Received message
+enum Incoming {
+    /// Converted message
+    Message(WsMessage),
+    /// Blob message, being read
+    Blob(BlobSlot),
+}
+
 /// A futures 0.3 Sink/Stream of [WsMessage]. Created with [WsMeta::connect](crate::WsMeta::connect).
 ///
 /// ## Closing the connection
@@ -45,7 +58,7 @@ pub struct WsStream {
     ws: Arc<WebSocket>,
 
     // The queue of received messages
-    queue: Arc<RefCell<VecDeque<WsMessage>>>,
+    queue: Arc<RefCell<VecDeque<Incoming>>>,
 
     // Last waker of task that wants to read incoming messages to be woken up on a new message
     waker: Arc<RefCell<Option<Waker>>>,
@@ -86,8 +99,24 @@ impl WsStream {
         // Send the incoming ws messages to the WsMeta object
         #[allow(trivial_casts)]
         let on_msg = Closure::wrap(Box::new(move |msg_evt: MessageEvent| {
+            if let Ok(blob) = msg_evt.data().dyn_into::<Blob>() {
+                // Read it asynchronously, keeping its position in the queue
+                let slot: BlobSlot = Rc::new(RefCell::new(None));
+                q2.borrow_mut().push_back(Incoming::Blob(slot.clone()));
+
+                let w3 = w2.clone();
+                task::spawn(async move {
+                    *slot.borrow_mut() = Some(read_blob(blob).await);
+
+                    if let Some(w) = w3.borrow_mut().take() {
+                        w.wake()
+                    }
+                });
+                return;
+            }
+
             match WsMessage::try_from(msg_evt) {
-                Ok(msg) => q2.borrow_mut().push_back(msg),
+                Ok(msg) => q2.borrow_mut().push_back(Incoming::Message(msg)),
                 Err(err) => notify(ph2.clone(), WsEvent::WsErr(err)),
             }
 
@@ -216,8 +245,22 @@ impl Stream for WsStream {
                 _ => None.into(),
             }
         } else {
+            let mut queue = self.queue.borrow_mut();
+
+            // Wait for the Blob at the front to be read, to preserve the order
+            if let Some(Incoming::Blob(slot)) = queue.front() {
+                if slot.borrow().is_none() {
+                    *self.waker.borrow_mut() = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+
             // As long as there is things in the queue, just keep reading
-            self.queue.borrow_mut().pop_front().map(Ok).into()
+            match queue.pop_front() {
+                Some(Incoming::Message(msg)) => Poll::Ready(Some(Ok(msg))),
+                Some(Incoming::Blob(slot)) => Poll::Ready(slot.borrow_mut().take()),
+                None => Poll::Ready(None),
+            }
         }
     }
 }